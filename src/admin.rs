@@ -0,0 +1,202 @@
+//! admin api, served on its own listen address so it can be kept off the
+//! public network. every request must carry `authorization: bearer <token>`.
+//!
+//! `POST /purge` takes a json body selecting what to purge:
+//! - `{"url": "/path?query"}` one url, with all of its variants
+//! - `{"prefix": "/static/"}` every url starting with the prefix
+//! - `{"glob": "/img/*.png"}` every url matching the glob (`*` and `?`)
+//! - `{"tag": "product-42"}` every response tagged by the origin with
+//!   `surrogate-key` / `cache-tag`; a list of tags is accepted too
+//! - `{"all": true}` everything
+//!
+//! adding `"soft": true` marks the entries stale instead of removing them.
+
+use crate::cache::{CdnCache, PurgeTarget};
+use crate::cache_key::CacheKeyPolicy;
+use crate::config::Config;
+use crate::forwarding::Forwarding;
+use crate::util::{Result, ShadowError};
+
+use actix_web::{
+    dev::Server, http::header, post, web, App, HttpRequest, HttpResponse,
+    HttpServer,
+};
+use log::info;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+struct AdminState {
+    cache: Arc<CdnCache>,
+    token: String,
+}
+
+/// compares in constant time so the token cannot be guessed byte by byte
+fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn is_authorized(req: &HttpRequest, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        // the auth scheme is case-insensitive (rfc 9110, section 11.1)
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .is_some_and(|(_, given)| token_matches(given.trim().as_bytes(), token.as_bytes()))
+}
+
+/// reads the tags to purge from the request body, a single tag or a list
+fn purge_tags(body: &Value) -> Vec<String> {
+    match &body["tag"] {
+        Value::String(tag) => vec![tag.clone()],
+        Value::Array(tags) => tags
+            .iter()
+            .filter_map(|t| t.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// reads the purge target from the request body, `None` if it names none.
+/// absolute urls are turned into keys by the cache key policy, anything else
+/// is matched against the keys as they are.
+fn purge_target(body: &Value, policy: &CacheKeyPolicy) -> Option<PurgeTarget> {
+    if let Some(url) = body["url"].as_str() {
+        return Some(PurgeTarget::Exact(policy.purge_key(url)));
+    }
+    if let Some(prefix) = body["prefix"].as_str() {
+        return Some(PurgeTarget::Prefix(policy.purge_key(prefix)));
+    }
+    if let Some(glob) = body["glob"].as_str() {
+        return Some(PurgeTarget::Glob(policy.purge_key(glob)));
+    }
+    (body["all"].as_bool() == Some(true)).then_some(PurgeTarget::All)
+}
+
+#[post("/purge")]
+async fn purge(req: HttpRequest, body: web::Bytes, state: web::Data<AdminState>) -> HttpResponse {
+    if !is_authorized(&req, &state.token) {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .json(json!({ "error": "missing or invalid admin token" }));
+    }
+
+    let Ok(body) = serde_json::from_slice::<Value>(&body) else {
+        return HttpResponse::BadRequest().json(json!({ "error": "body must be a json object" }));
+    };
+    let soft = body["soft"].as_bool().unwrap_or(false);
+    let kind = if soft { "soft" } else { "hard" };
+
+    let tags = purge_tags(&body);
+    let purged = if !tags.is_empty() {
+        let mut purged = 0;
+        for tag in &tags {
+            purged += state.cache.purge_tag(tag, soft).await;
+        }
+        info!("{} purge of tags {:?}: {} entries", kind, tags, purged);
+        purged
+    } else if let Some(target) = purge_target(&body, state.cache.key_policy()) {
+        let purged = state.cache.purge(&target, soft).await;
+        info!("{} purge of {:?}: {} entries", kind, target, purged);
+        purged
+    } else {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "expected one of \"url\", \"prefix\", \"glob\", \"tag\" or \"all\": true"
+        }));
+    };
+    HttpResponse::Ok().json(json!({ "purged": purged, "soft": soft }))
+}
+
+/// builds the admin server bound to the configured admin address. refuses to
+/// start without a token, an unauthenticated purge endpoint is never wanted.
+pub fn server(config: &Config, cache: Arc<CdnCache>) -> Result<Server> {
+    let addr = config
+        .admin_listen_addr
+        .as_deref()
+        .ok_or_else(|| ShadowError::Config("admin listen address is not set".into()))?;
+    let token = match config.admin_token.as_deref().map(str::trim) {
+        Some(token) if !token.is_empty() => token.to_string(),
+        _ => {
+            return Err(ShadowError::Config(
+                "ADMIN_TOKEN is required when the admin api is enabled".into(),
+            ))
+        }
+    };
+
+    info!("admin api listening on {}", addr);
+    let state = web::Data::new(AdminState { cache, token });
+    let forwarding = Forwarding::from_config(config);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .wrap(forwarding.access_logger())
+            .service(purge)
+    })
+    .workers(1)
+    .bind(addr)?
+    .shutdown_timeout(30)
+    .run();
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn policy() -> CacheKeyPolicy {
+        CacheKeyPolicy::from_json(&json!({ "default": { "include_host": true } })).unwrap()
+    }
+
+    fn target(body: Value) -> Option<String> {
+        purge_target(&body, &policy()).map(|target| format!("{:?}", target))
+    }
+
+    #[test]
+    fn purge_targets_are_read_from_the_body() {
+        assert_eq!(target(json!({ "url": "/page?q=1" })).unwrap(), r#"Exact("/page?q=1")"#);
+        assert_eq!(target(json!({ "prefix": "/static/" })).unwrap(), r#"Prefix("/static/")"#);
+        assert_eq!(target(json!({ "glob": "/img/*.png" })).unwrap(), r#"Glob("/img/*.png")"#);
+        assert_eq!(target(json!({ "all": true })).unwrap(), "All");
+        assert_eq!(target(json!({ "all": false })), None);
+        assert_eq!(target(json!({ "path": "/page" })), None);
+    }
+
+    #[test]
+    fn absolute_urls_are_turned_into_keys() {
+        let key = policy().key("https", "www.example.com", "/page", &http::HeaderMap::new());
+        assert_eq!(
+            target(json!({ "url": "https://www.example.com/page" })).unwrap(),
+            format!("Exact({:?})", key)
+        );
+    }
+
+    #[test]
+    fn tags_are_a_string_or_a_list() {
+        assert_eq!(purge_tags(&json!({ "tag": "a" })), vec!["a"]);
+        assert_eq!(purge_tags(&json!({ "tag": ["a", 1, "b"] })), vec!["a", "b"]);
+        assert!(purge_tags(&json!({ "url": "/" })).is_empty());
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        let authorized = |value: &str| {
+            let req = TestRequest::default()
+                .insert_header((header::AUTHORIZATION, value))
+                .to_http_request();
+            is_authorized(&req, "s3cret")
+        };
+        assert!(authorized("Bearer s3cret"));
+        assert!(authorized("bearer s3cret"));
+        assert!(authorized("BEARER s3cret"));
+        assert!(!authorized("Bearer wrong"));
+        assert!(!authorized("Basic s3cret"));
+        assert!(!authorized("s3cret"));
+        assert!(!is_authorized(&TestRequest::default().to_http_request(), "s3cret"));
+    }
+}
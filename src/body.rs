@@ -0,0 +1,210 @@
+//! streaming helpers for origin response bodies, so large objects pass
+//! through to the client without being buffered whole.
+
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use hyper::Body;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::oneshot;
+
+/// passes an origin body through while keeping a copy for the cache. the copy
+/// is abandoned as soon as it would exceed the limit; when the body ends
+/// normally, the complete copy is sent to the receiver returned by
+/// [`TeeBody::new`]. if the body fails or is dropped early, the receiver
+/// gets nothing.
+pub struct TeeBody {
+    inner: Body,
+    copy: Option<BytesMut>,
+    limit: usize,
+    done: Option<oneshot::Sender<Bytes>>,
+}
+
+impl TeeBody {
+    pub fn new(inner: Body, limit: usize) -> (Self, oneshot::Receiver<Bytes>) {
+        let (done, receiver) = oneshot::channel();
+        let tee = Self {
+            inner,
+            copy: Some(BytesMut::new()),
+            limit,
+            done: Some(done),
+        };
+        (tee, receiver)
+    }
+
+    fn abandon_copy(&mut self) {
+        self.copy = None;
+        self.done = None;
+    }
+}
+
+impl Stream for TeeBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                match &mut this.copy {
+                    Some(copy) if copy.len() + chunk.len() <= this.limit => copy.extend_from_slice(&chunk),
+                    Some(_) => {
+                        log::debug!("response exceeds {} bytes, not caching it", this.limit);
+                        this.abandon_copy();
+                    }
+                    None => {}
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(None) => {
+                if let (Some(copy), Some(done)) = (this.copy.take(), this.done.take()) {
+                    // nobody listening any more is fine
+                    let _ = done.send(copy.freeze());
+                }
+                Poll::Ready(None)
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.abandon_copy();
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// outcome of reading a body up to a size limit
+pub enum Limited {
+    /// the whole body fit
+    Complete(Bytes),
+    /// the body is larger: what was read so far, and the rest
+    Overflow(Bytes, Body),
+}
+
+/// reads a body into memory, stopping once more than `limit` bytes arrived
+pub async fn read_limited(mut body: Body, limit: usize) -> Result<Limited, hyper::Error> {
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        buffer.extend_from_slice(&chunk?);
+        if buffer.len() > limit {
+            return Ok(Limited::Overflow(buffer.freeze(), body));
+        }
+    }
+    Ok(Limited::Complete(buffer.freeze()))
+}
+
+/// streams `prefix` followed by the rest of `body`
+pub fn chain(prefix: Bytes, body: Body) -> impl Stream<Item = Result<Bytes, hyper::Error>> {
+    stream::once(async move { Ok(prefix) }).chain(body)
+}
+
+/// streams only bytes `start..=end` of a body, without reading past `end`
+pub fn slice(body: Body, start: u64, end: u64) -> impl Stream<Item = Result<Bytes, hyper::Error>> {
+    stream::unfold((body, 0u64), move |(mut body, mut offset)| async move {
+        loop {
+            if offset > end {
+                return None;
+            }
+            match body.next().await? {
+                // nothing follows an error
+                Err(e) => return Some((Err(e), (body, u64::MAX))),
+                Ok(chunk) => {
+                    let chunk_start = offset;
+                    offset += chunk.len() as u64;
+                    if offset <= start {
+                        continue;
+                    }
+                    let from = start.saturating_sub(chunk_start) as usize;
+                    let to = ((end + 1).min(offset) - chunk_start) as usize;
+                    return Some((Ok(chunk.slice(from..to)), (body, offset)));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a body arriving in the given chunks, failing where a chunk is `None`
+    fn body(chunks: &[Option<&'static str>]) -> Body {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks
+            .iter()
+            .map(|chunk| match chunk {
+                Some(data) => Ok(Bytes::from_static(data.as_bytes())),
+                None => Err(std::io::Error::other("connection reset")),
+            })
+            .collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    async fn collect(stream: impl Stream<Item = Result<Bytes, hyper::Error>>) -> (Vec<Bytes>, bool) {
+        let items: Vec<_> = stream.collect().await;
+        let failed = items.iter().any(Result::is_err);
+        (items.into_iter().filter_map(Result::ok).collect(), failed)
+    }
+
+    #[tokio::test]
+    async fn tee_keeps_a_copy_up_to_the_limit() {
+        let (tee, copy) = TeeBody::new(body(&[Some("abc"), Some("def")]), 6);
+        assert_eq!(collect(tee).await, (vec![Bytes::from("abc"), Bytes::from("def")], false));
+        assert_eq!(copy.await.unwrap(), "abcdef");
+    }
+
+    #[tokio::test]
+    async fn tee_abandons_a_copy_over_the_limit() {
+        let (tee, copy) = TeeBody::new(body(&[Some("abc"), Some("def"), Some("g")]), 5);
+        // the client still gets everything
+        assert_eq!(collect(tee).await.0.concat(), b"abcdefg");
+        assert!(copy.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tee_sends_nothing_after_an_error_or_an_early_drop() {
+        let (tee, copy) = TeeBody::new(body(&[Some("abc"), None]), 100);
+        assert_eq!(collect(tee).await, (vec![Bytes::from("abc")], true));
+        assert!(copy.await.is_err());
+
+        let (mut tee, copy) = TeeBody::new(body(&[Some("abc"), Some("def")]), 100);
+        assert_eq!(tee.next().await.unwrap().unwrap(), "abc");
+        drop(tee);
+        assert!(copy.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_limited_stops_after_the_limit() {
+        let Ok(Limited::Complete(all)) = read_limited(body(&[Some("abc"), Some("def")]), 6).await else {
+            panic!("a body exactly at the limit is complete");
+        };
+        assert_eq!(all, "abcdef");
+
+        let Ok(Limited::Overflow(read, rest)) = read_limited(body(&[Some("abc"), Some("def"), Some("ghi")]), 4).await
+        else {
+            panic!("a body over the limit overflows");
+        };
+        assert_eq!(read, "abcdef");
+        assert_eq!(collect(chain(read, rest)).await.0.concat(), b"abcdefghi");
+
+        assert!(read_limited(body(&[Some("abc"), None]), 100).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn slice_spans_chunk_edges() {
+        let chunks = [Some("abc"), Some("def"), Some("ghi")];
+        assert_eq!(collect(slice(body(&chunks), 2, 4)).await, (vec![Bytes::from("c"), Bytes::from("de")], false));
+        assert_eq!(collect(slice(body(&chunks), 3, 5)).await, (vec![Bytes::from("def")], false));
+        assert_eq!(collect(slice(body(&chunks), 8, 100)).await, (vec![Bytes::from("i")], false));
+        assert_eq!(collect(slice(body(&chunks), 0, 0)).await, (vec![Bytes::from("a")], false));
+    }
+
+    #[tokio::test]
+    async fn slice_never_reads_past_its_end() {
+        // the failing chunk after the range is never polled
+        let (chunks, failed) = collect(slice(body(&[Some("abc"), Some("def"), None]), 1, 5)).await;
+        assert_eq!(chunks.concat(), b"bcdef");
+        assert!(!failed);
+
+        let (chunks, failed) = collect(slice(body(&[Some("abc"), None, Some("def")]), 1, 5)).await;
+        assert_eq!(chunks.concat(), b"bc");
+        assert!(failed);
+    }
+}
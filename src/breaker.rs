@@ -0,0 +1,469 @@
+//! per-origin circuit breaker.
+//!
+//! while closed, the outcome of every origin request is counted over a
+//! window. once at least `min_requests` were seen and the share of errors
+//! (connection failures, timeouts, 5xx) or of slow responses crosses its
+//! threshold, the breaker opens and requests fail fast for `open_ms`. then it
+//! is half-open: `half_open_requests` trial requests go through, and the
+//! breaker closes if they all succeed or opens again on the first failure.
+//!
+//! ```json
+//! "circuit_breaker": {
+//!   "window_ms": 10000, "min_requests": 20, "error_rate": 0.5,
+//!   "slow_call_ms": 2000, "slow_call_rate": 0.8, "open_ms": 30000, "half_open_requests": 5
+//! }
+//! ```
+
+use crate::config::Config;
+use crate::util::{Result, ShadowError};
+use log::{info, warn};
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// thresholds of a breaker
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+    pub window: Duration,
+    pub min_requests: u32,
+    /// share of failed requests in the window that opens the breaker
+    pub error_rate: f64,
+    /// responses slower than this count as slow, `None` ignores latency
+    pub slow_call: Option<Duration>,
+    /// share of slow requests in the window that opens the breaker
+    pub slow_call_rate: f64,
+    pub open_for: Duration,
+    pub half_open_requests: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            error_rate: 0.5,
+            slow_call: None,
+            slow_call_rate: 1.0,
+            open_for: Duration::from_secs(30),
+            half_open_requests: 5,
+        }
+    }
+}
+
+fn config_error(message: impl Into<String>) -> ShadowError {
+    ShadowError::Config(format!("circuit_breaker: {}", message.into()))
+}
+
+impl BreakerConfig {
+    /// the breaker of the `ORIGIN_URL` origin, `None` unless enabled
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        if !config.origin_circuit_breaker {
+            return Ok(None);
+        }
+        let positive = |name: &str, value: u64| {
+            (value > 0)
+                .then_some(value)
+                .ok_or_else(|| config_error(format!("{} must be positive", name)))
+        };
+        let millis = |name: &str, value: u64| positive(name, value).map(Duration::from_millis);
+        let count = |name: &str, value: u32| positive(name, value.into()).map(|n| n as u32);
+        let rate = |name: &str, percent: u32| {
+            (1..=100)
+                .contains(&percent)
+                .then_some(percent as f64 / 100.0)
+                .ok_or_else(|| config_error(format!("{} must be between 1 and 100", name)))
+        };
+        let slow_call_ms = config.origin_breaker_slow_call_ms;
+        Ok(Some(Self {
+            window: millis("ORIGIN_BREAKER_WINDOW_MS", config.origin_breaker_window_ms)?,
+            min_requests: count("ORIGIN_BREAKER_MIN_REQUESTS", config.origin_breaker_min_requests)?,
+            error_rate: rate("ORIGIN_BREAKER_ERROR_PERCENT", config.origin_breaker_error_percent)?,
+            slow_call: (slow_call_ms > 0).then(|| Duration::from_millis(slow_call_ms)),
+            slow_call_rate: rate(
+                "ORIGIN_BREAKER_SLOW_CALL_PERCENT",
+                config.origin_breaker_slow_call_percent,
+            )?,
+            open_for: millis("ORIGIN_BREAKER_OPEN_MS", config.origin_breaker_open_ms)?,
+            half_open_requests: count(
+                "ORIGIN_BREAKER_HALF_OPEN_REQUESTS",
+                config.origin_breaker_half_open_requests,
+            )?,
+        }))
+    }
+
+    pub fn from_json(options: &Map<String, Value>) -> Result<Self> {
+        let mut config = Self::default();
+        for (option, value) in options {
+            let number = || {
+                value
+                    .as_u64()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| config_error(format!("`{}` must be a positive number", option)))
+            };
+            let rate = || {
+                value
+                    .as_f64()
+                    .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                    .ok_or_else(|| config_error(format!("`{}` must be between 0 and 1", option)))
+            };
+            match option.as_str() {
+                "window_ms" => config.window = Duration::from_millis(number()?),
+                "min_requests" => config.min_requests = number()? as u32,
+                "error_rate" => config.error_rate = rate()?,
+                "slow_call_ms" => config.slow_call = Some(Duration::from_millis(number()?)),
+                "slow_call_rate" => config.slow_call_rate = rate()?,
+                "open_ms" => config.open_for = Duration::from_millis(number()?),
+                "half_open_requests" => config.half_open_requests = number()? as u32,
+                _ => return Err(config_error(format!("unknown option `{}`", option))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn label(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    slow: u32,
+    open_until: Instant,
+    /// trial requests let through while half-open, and how many succeeded
+    trials: u32,
+    trial_successes: u32,
+}
+
+/// circuit breaker of one origin
+pub struct CircuitBreaker {
+    origin: String,
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// admission of one request; its outcome must be recorded with
+/// [`Permit::record`], a permit dropped without one is not counted
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+    started: Instant,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(success, self.started.elapsed(), self.trial);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            // give the trial slot back, the request never finished
+            let mut inner = self.breaker.inner.lock().unwrap();
+            if inner.state == BreakerState::HalfOpen {
+                inner.trials = inner.trials.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(origin: &str, config: BreakerConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            origin: origin.to_string(),
+            config,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                window_start: now,
+                requests: 0,
+                failures: 0,
+                slow: 0,
+                open_until: now,
+                trials: 0,
+                trial_successes: 0,
+            }),
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// lets a request through, or returns how long until the breaker will
+    /// try the origin again
+    pub fn acquire(&self) -> std::result::Result<Permit<'_>, Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        if inner.state == BreakerState::Open && now >= inner.open_until {
+            info!("circuit breaker of origin {} is half-open, sending trial requests", self.origin);
+            inner.state = BreakerState::HalfOpen;
+            inner.trials = 0;
+            inner.trial_successes = 0;
+        }
+        let trial = match inner.state {
+            BreakerState::Closed => false,
+            BreakerState::HalfOpen if inner.trials < self.config.half_open_requests => {
+                inner.trials += 1;
+                true
+            }
+            BreakerState::HalfOpen | BreakerState::Open => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(inner.open_until.saturating_duration_since(now));
+            }
+        };
+        Ok(Permit {
+            breaker: self,
+            trial,
+            started: now,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, success: bool, latency: Duration, trial: bool) {
+        let slow = self.config.slow_call.is_some_and(|limit| latency > limit);
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::HalfOpen if trial => {
+                if !success || slow {
+                    warn!("trial request to origin {} failed, circuit breaker opens again", self.origin);
+                    self.open(&mut inner);
+                } else {
+                    inner.trial_successes += 1;
+                    if inner.trial_successes >= self.config.half_open_requests {
+                        info!("circuit breaker of origin {} closed", self.origin);
+                        inner.state = BreakerState::Closed;
+                        self.reset_window(&mut inner);
+                    }
+                }
+            }
+            BreakerState::Closed => {
+                if inner.window_start.elapsed() > self.config.window {
+                    self.reset_window(&mut inner);
+                }
+                inner.requests += 1;
+                inner.failures += u32::from(!success);
+                inner.slow += u32::from(slow);
+                if inner.requests >= self.config.min_requests {
+                    let requests = inner.requests as f64;
+                    let error_rate = inner.failures as f64 / requests;
+                    let slow_rate = inner.slow as f64 / requests;
+                    if error_rate >= self.config.error_rate {
+                        warn!(
+                            "{:.0}% of {} requests to origin {} failed, circuit breaker opens for {:?}",
+                            error_rate * 100.0,
+                            inner.requests,
+                            self.origin,
+                            self.config.open_for
+                        );
+                        self.open(&mut inner);
+                    } else if self.config.slow_call.is_some() && slow_rate >= self.config.slow_call_rate {
+                        warn!(
+                            "{:.0}% of {} requests to origin {} were slow, circuit breaker opens for {:?}",
+                            slow_rate * 100.0,
+                            inner.requests,
+                            self.origin,
+                            self.config.open_for
+                        );
+                        self.open(&mut inner);
+                    }
+                }
+            }
+            // requests admitted before the breaker opened say nothing new
+            _ => {}
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        inner.state = BreakerState::Open;
+        inner.open_until = Instant::now() + self.config.open_for;
+        self.opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset_window(&self, inner: &mut Inner) {
+        inner.window_start = Instant::now();
+        inner.requests = 0;
+        inner.failures = 0;
+        inner.slow = 0;
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// times the breaker opened
+    pub fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    /// requests failed fast while open
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn breaker(open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "web",
+            BreakerConfig {
+                min_requests: 4,
+                error_rate: 0.5,
+                open_for,
+                half_open_requests: 2,
+                ..BreakerConfig::default()
+            },
+        )
+    }
+
+    fn record(breaker: &CircuitBreaker, outcomes: &[bool]) {
+        for &success in outcomes {
+            breaker.acquire().unwrap().record(success);
+        }
+    }
+
+    #[test]
+    fn opens_once_the_error_rate_is_reached() {
+        let breaker = breaker(Duration::from_secs(60));
+        // too few requests to judge
+        record(&breaker, &[false, false, false]);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        record(&breaker, &[true]);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.opened(), 1);
+
+        let retry_after = breaker.acquire().err().unwrap();
+        assert!(retry_after > Duration::from_secs(50));
+        assert_eq!(breaker.rejected(), 1);
+    }
+
+    #[test]
+    fn stays_closed_below_the_error_rate() {
+        let breaker = breaker(Duration::from_secs(60));
+        record(&breaker, &[false, true, true, true, false, true, true]);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn half_open_trials_close_or_reopen_it() {
+        let breaker = breaker(Duration::ZERO);
+        record(&breaker, &[false; 4]);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // only `half_open_requests` trials are let through at once
+        let first = breaker.acquire().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        let second = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        first.record(true);
+        second.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        record(&breaker, &[false; 4]);
+        breaker.acquire().unwrap().record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.opened(), 3);
+    }
+
+    #[test]
+    fn unrecorded_trials_give_their_slot_back() {
+        let breaker = breaker(Duration::ZERO);
+        record(&breaker, &[false; 4]);
+        drop(breaker.acquire().unwrap());
+        drop(breaker.acquire().unwrap());
+        let trial = breaker.acquire().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        trial.record(true);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn slow_calls_open_it_too() {
+        let breaker = CircuitBreaker::new(
+            "web",
+            BreakerConfig {
+                min_requests: 2,
+                slow_call: Some(Duration::from_millis(5)),
+                slow_call_rate: 1.0,
+                ..BreakerConfig::default()
+            },
+        );
+        for _ in 0..2 {
+            let permit = breaker.acquire().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            permit.record(true);
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn config_is_read_from_json() {
+        let options = json!({ "window_ms": 5000, "error_rate": 0.25, "slow_call_ms": 100 });
+        let config = BreakerConfig::from_json(options.as_object().unwrap()).unwrap();
+        assert_eq!(config.window, Duration::from_secs(5));
+        assert_eq!(config.error_rate, 0.25);
+        assert_eq!(config.slow_call, Some(Duration::from_millis(100)));
+        assert_eq!(config.min_requests, 20);
+
+        for invalid in [json!({ "error_rate": 1.5 }), json!({ "open_ms": 0 }), json!({ "size": 1 })] {
+            assert!(BreakerConfig::from_json(invalid.as_object().unwrap()).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn config_is_read_from_the_command_line() {
+        use clap::Parser;
+        let parse = |args: &[&str]| {
+            let config = Config::parse_from(["shadowstep"].iter().chain(args));
+            BreakerConfig::from_config(&config)
+        };
+        assert!(parse(&[]).unwrap().is_none());
+
+        let config = parse(&[
+            "--origin-circuit-breaker",
+            "true",
+            "--origin-breaker-error-percent",
+            "25",
+            "--origin-breaker-slow-call-ms",
+            "100",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.error_rate, 0.25);
+        assert_eq!(config.slow_call, Some(Duration::from_millis(100)));
+        assert_eq!(config.slow_call_rate, 1.0);
+        assert_eq!(config.min_requests, 20);
+
+        for invalid in [
+            ["--origin-breaker-error-percent", "0"],
+            ["--origin-breaker-slow-call-percent", "101"],
+            ["--origin-breaker-open-ms", "0"],
+        ] {
+            assert!(parse(&["--origin-circuit-breaker", "true", invalid[0], invalid[1]]).is_err());
+        }
+    }
+}
@@ -1,8 +1,17 @@
+use crate::cache_key::{CacheKeyPolicy, EXTRAS_SEPARATOR};
+use crate::config::Config;
+use crate::disk::DiskCache;
+use crate::freshness::{self, StaleWindows};
+use crate::util::Result;
 use bytes::Bytes;
-use http::{HeaderMap, StatusCode};
+use http::{header, HeaderMap, HeaderName, StatusCode};
 use moka::future::Cache;
-use std::sync::Arc;
-use std::time::Duration;
+use moka::notification::RemovalCause;
+use moka::Expiry;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// cached response containing status code, headers, and body
 #[derive(Clone)]
@@ -10,63 +19,911 @@ pub struct CachedResponse {
     pub status: StatusCode,
     pub headers: HeaderMap,
     pub body: Bytes,
+    /// how long the response stays fresh after `stored_at`
+    pub ttl: Duration,
+    pub stored_at: Instant,
+    /// age the response already had when it was received from the origin
+    pub initial_age: Duration,
+    /// how long past `ttl` the response may still be served stale
+    pub stale: StaleWindows,
 }
 
-/// cache for cdn responses with configurable size and ttl
+impl CachedResponse {
+    /// builds a cache entry fresh for `ttl` from now. any `age` header is
+    /// folded into `initial_age` and regenerated when the entry is served.
+    pub fn new(
+        status: StatusCode,
+        mut headers: HeaderMap,
+        body: Bytes,
+        ttl: Duration,
+        stale: StaleWindows,
+    ) -> Self {
+        let initial_age = freshness::initial_age(&headers);
+        headers.remove(header::AGE);
+        Self {
+            status,
+            headers,
+            body,
+            ttl,
+            stored_at: Instant::now(),
+            initial_age,
+            stale,
+        }
+    }
+
+    /// current age of the response, as sent in the `age` header
+    pub fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    /// approximate memory held by the entry: body, headers and fixed overhead
+    pub fn size_bytes(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        std::mem::size_of::<Self>() + headers + self.body.len()
+    }
+
+    /// true while the response is within its freshness lifetime
+    pub fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.ttl
+    }
+
+    /// how long the response has been stale, zero while it is fresh
+    pub fn staleness(&self) -> Duration {
+        self.stored_at.elapsed().saturating_sub(self.ttl)
+    }
+
+    /// true when the stale response may be served while it is refreshed in the background
+    pub fn can_serve_while_revalidating(&self) -> bool {
+        !self.is_fresh() && self.staleness() < self.stale.while_revalidate
+    }
+
+    /// true when the stale response may be served in place of an origin error
+    pub fn can_serve_on_error(&self) -> bool {
+        !self.is_fresh() && self.staleness() < self.stale.if_error
+    }
+
+    /// true when the response carries an `etag` or `last-modified` the
+    /// origin can validate a conditional request against
+    pub fn has_validators(&self) -> bool {
+        freshness::has_validators(&self.headers)
+    }
+
+    /// stored headers updated with those of a `304 not modified` received
+    /// while revalidating (rfc 9111 section 4.3.4). framing headers of the
+    /// 304 are ignored since the stored body is kept.
+    pub fn headers_refreshed_by(&self, not_modified: &HeaderMap) -> HeaderMap {
+        let mut headers = self.headers.clone();
+        for name in not_modified.keys() {
+            if matches!(
+                *name,
+                header::CONTENT_LENGTH | header::TRANSFER_ENCODING | header::CONNECTION
+            ) {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        headers
+    }
+}
+
+/// expires each entry after its own freshness lifetime, keeping entries that
+/// can be revalidated or served stale around for a while longer
+struct ResponseExpiry {
+    stale_retention: Duration,
+}
+
+/// how much longer an entry should be kept from now: its remaining freshness
+/// plus the longest window in which it may still be used stale
+fn remaining_lifetime(value: &CachedResponse, stale_retention: Duration) -> Duration {
+    let retention = if value.has_validators() {
+        stale_retention
+    } else {
+        Duration::ZERO
+    };
+    let total = value.ttl
+        + retention
+            .max(value.stale.while_revalidate)
+            .max(value.stale.if_error);
+    total.saturating_sub(value.stored_at.elapsed())
+}
+
+impl ResponseExpiry {
+    fn lifetime(&self, value: &CachedResponse) -> Duration {
+        remaining_lifetime(value, self.stale_retention)
+    }
+}
+
+impl Expiry<String, Arc<CachedResponse>> for ResponseExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &Arc<CachedResponse>,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(self.lifetime(value))
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &Arc<CachedResponse>,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.lifetime(value))
+    }
+}
+
+/// status classes with their own hit/miss accounting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusClass {
+    Success,
+    Redirect,
+    ClientError,
+    ServerError,
+}
+
+impl StatusClass {
+    pub const ALL: [StatusClass; 4] = [
+        StatusClass::Success,
+        StatusClass::Redirect,
+        StatusClass::ClientError,
+        StatusClass::ServerError,
+    ];
+
+    /// class of a status code; informational responses are counted as success
+    pub fn of(status: StatusCode) -> Self {
+        match status.as_u16() {
+            300..=399 => StatusClass::Redirect,
+            400..=499 => StatusClass::ClientError,
+            500..=599 => StatusClass::ServerError,
+            _ => StatusClass::Success,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            StatusClass::Success => "2xx",
+            StatusClass::Redirect => "3xx",
+            StatusClass::ClientError => "4xx",
+            StatusClass::ServerError => "5xx",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// point-in-time snapshot of cache counters, as reported by the health endpoint
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub revalidations: u64,
+    pub stale: u64,
+    pub items: u64,
+    /// weighted size of all entries, in bytes
+    pub bytes_used: u64,
+    pub capacity_bytes: u64,
+    /// memory misses answered by the disk tier
+    pub disk_hits: u64,
+    pub disk_items: u64,
+    pub disk_bytes_used: u64,
+    /// fresh hits per [`StatusClass`], indexed in [`StatusClass::ALL`] order
+    pub hits_by_class: [u64; 4],
+    /// responses fetched from the origin per [`StatusClass`]
+    pub misses_by_class: [u64; 4],
+}
+
+impl CacheStats {
+    /// fresh hits on responses of the given status class
+    pub fn hits_for(&self, class: StatusClass) -> u64 {
+        self.hits_by_class[class.index()]
+    }
+
+    /// origin fetches that returned a response of the given status class
+    pub fn misses_for(&self, class: StatusClass) -> u64 {
+        self.misses_by_class[class.index()]
+    }
+
+    /// fraction of lookups served from cache, 0.0 when nothing was looked up yet
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total > 0 {
+            self.hits as f64 / total as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// builds the secondary key of a variant: the primary key followed by the
+/// request's values for each header named in the stored `vary`
+fn variant_key(primary_key: &str, vary: &[HeaderName], request_headers: &HeaderMap) -> String {
+    let mut key = String::from(primary_key);
+    for name in vary {
+        key.push('\n');
+        key.push_str(name.as_str());
+        key.push(':');
+        let values = request_headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        key.push_str(&values.join(","));
+    }
+    key
+}
+
+/// whether responses with this status are ever cached: successful ones,
+/// redirects, 404/410 and the origin errors a stale entry could stand in for
+pub fn caches_status(status: StatusCode) -> bool {
+    match status {
+        // a partial body must never stand in for the full one
+        StatusCode::PARTIAL_CONTENT => false,
+        s if s.is_success() => true,
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT
+        | StatusCode::NOT_FOUND
+        | StatusCode::GONE
+        | StatusCode::INTERNAL_SERVER_ERROR
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => true,
+        _ => false,
+    }
+}
+
+/// fastly-style tags, space separated
+const SURROGATE_KEY: &str = "surrogate-key";
+/// cloudflare-style tags, comma separated
+const CACHE_TAG: &str = "cache-tag";
+
+/// true for the headers an origin uses to tag responses for purging. they are
+/// kept on stored entries but never sent on to clients.
+pub fn is_tag_header(name: &HeaderName) -> bool {
+    name.as_str() == SURROGATE_KEY || name.as_str() == CACHE_TAG
+}
+
+/// tags listed in the `surrogate-key` and `cache-tag` headers, de-duplicated
+pub fn response_tags(headers: &HeaderMap) -> Vec<String> {
+    let surrogate_keys = headers
+        .get_all(SURROGATE_KEY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(str::split_whitespace);
+    let cache_tags = headers
+        .get_all(CACHE_TAG)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim);
+    let mut tags: Vec<String> = Vec::new();
+    for tag in surrogate_keys.chain(cache_tags).filter(|t| !t.is_empty()) {
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    tags
+}
+
+/// tag -> keys of the in-memory entries carrying that tag
+#[derive(Default)]
+struct TagIndex {
+    keys: HashMap<String, HashSet<String>>,
+}
+
+impl TagIndex {
+    fn tag(&mut self, key: &str, headers: &HeaderMap) {
+        for tag in response_tags(headers) {
+            self.keys.entry(tag).or_default().insert(key.to_string());
+        }
+    }
+
+    fn untag(&mut self, key: &str, headers: &HeaderMap) {
+        for tag in response_tags(headers) {
+            if let Some(keys) = self.keys.get_mut(&tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+/// primary key of a stored entry, stripping the variant suffix if any
+pub(crate) fn primary_key(key: &str) -> &str {
+    key.split_once('\n').map_or(key, |(primary, _)| primary)
+}
+
+/// matches `text` against a glob where `*` matches any run of characters
+/// (including none) and `?` matches exactly one
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // let the last `*` swallow one more character and retry
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// selects the entries affected by [`CdnCache::purge`]. targets are matched
+/// against primary cache keys, so a match always covers every variant.
+#[derive(Clone, Debug)]
+pub enum PurgeTarget {
+    /// one cache key, whatever header and cookie values it was stored with
+    Exact(String),
+    /// every cache key starting with the prefix
+    Prefix(String),
+    /// every cache key matching a glob, see [`PurgeTarget::matches`]
+    Glob(String),
+    /// everything
+    All,
+}
+
+impl PurgeTarget {
+    /// true when the target covers the primary cache key. globs support `*`
+    /// for any run of characters and `?` for a single character.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            PurgeTarget::Exact(exact) => key
+                .strip_prefix(exact.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(EXTRAS_SEPARATOR)),
+            PurgeTarget::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            PurgeTarget::Glob(pattern) => glob_matches(pattern, key),
+            PurgeTarget::All => true,
+        }
+    }
+}
+
+/// cache for cdn responses with configurable size and per-entry ttl
+///
+/// responses without `vary` are stored under their primary key. responses
+/// with `vary` are stored once per variant under a secondary key, and the
+/// primary key maps to the header names that select between the variants.
+///
+/// when a disk tier is configured, every stored response is also written to
+/// disk in the background, and memory misses fall through to it.
+///
+/// entries are indexed by the tags in their `surrogate-key` / `cache-tag`
+/// headers, so everything depending on one entity can be purged at once.
 pub struct CdnCache {
     cache: Cache<String, Arc<CachedResponse>>,
+    variants: Cache<String, Arc<Vec<HeaderName>>>,
+    tags: Arc<Mutex<TagIndex>>,
+    disk: Option<Arc<DiskCache>>,
+    key_policy: CacheKeyPolicy,
+    default_ttl: Duration,
+    redirect_ttl: Duration,
+    not_found_ttl: Duration,
+    error_ttl: Duration,
+    stale_defaults: StaleWindows,
+    stale_retention: Duration,
+    capacity_bytes: u64,
+    max_object_size: u64,
+    hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    hits_by_class: [AtomicU64; 4],
+    misses_by_class: [AtomicU64; 4],
+    revalidations: AtomicU64,
+    stale: AtomicU64,
 }
 
 impl CdnCache {
-    /// creates a new cdn cache with specified size limit (in mb) and ttl (in seconds)
-    pub fn new(size_mb: u64, ttl_seconds: u64) -> Self {
-        // convert mb to estimated item count (rough approximation)
-        // assumption: average cached item is ~10kb including headers and metadata
-        let max_capacity = (size_mb * 1024 * 1024) / (10 * 1024);
-        
-        // create moka cache with time-based expiration
+    /// creates a new cdn cache from the configured size limit (in mb) and default ttl,
+    /// used for responses that carry no explicit freshness information. responses with
+    /// validators are retained for the configured stale retention past freshness.
+    ///
+    /// if a disk cache directory is configured, the disk tier is opened and
+    /// the entries persisted by a previous run become available again.
+    pub async fn new(config: &Config) -> Result<Self> {
+        let capacity_bytes = config.cache_size_mb * 1024 * 1024;
+        let stale_retention = Duration::from_secs(config.cache_stale_retention_seconds);
+        let tags = Arc::new(Mutex::new(TagIndex::default()));
+        let evicted_tags = tags.clone();
+
+        // create moka cache with per-entry expiration, weighing each entry by
+        // its real size so capacity is enforced in bytes
         let cache = Cache::builder()
-            .max_capacity(max_capacity)
-            .time_to_live(Duration::from_secs(ttl_seconds))
+            .max_capacity(capacity_bytes)
+            .weigher(|key: &String, value: &Arc<CachedResponse>| {
+                u32::try_from(key.len() + value.size_bytes()).unwrap_or(u32::MAX)
+            })
+            .expire_after(ResponseExpiry { stale_retention })
+            .support_invalidation_closures()
+            // replacements are re-tagged by the insert itself
+            .eviction_listener(move |key, value, cause| {
+                if cause != RemovalCause::Replaced {
+                    evicted_tags.lock().unwrap().untag(&key, &value.headers);
+                }
+            })
             .build();
-        
-        Self { cache }
+        // vary rules are tiny, an item count estimated at one per ~10kb of cache is plenty
+        let variants = Cache::builder()
+            .max_capacity(capacity_bytes / (10 * 1024))
+            .build();
+
+        let disk = match &config.disk_cache_dir {
+            Some(dir) => {
+                let (disk, vary_rules) =
+                    DiskCache::open(dir, config.disk_cache_size_mb * 1024 * 1024)?;
+                for rule in vary_rules {
+                    variants.insert(rule.primary_key, Arc::new(rule.vary)).await;
+                }
+                Some(Arc::new(disk))
+            }
+            None => None,
+        };
+
+        let key_policy = match &config.cache_key_config {
+            Some(path) => CacheKeyPolicy::load(path)?,
+            None => CacheKeyPolicy::default(),
+        };
+
+        Ok(Self {
+            cache,
+            variants,
+            tags,
+            disk,
+            key_policy,
+            default_ttl: Duration::from_secs(config.cache_ttl_seconds),
+            redirect_ttl: Duration::from_secs(config.cache_redirect_ttl_seconds),
+            not_found_ttl: Duration::from_secs(config.cache_not_found_ttl_seconds),
+            error_ttl: Duration::from_secs(config.cache_error_ttl_seconds),
+            stale_defaults: StaleWindows {
+                while_revalidate: Duration::from_secs(config.cache_stale_while_revalidate_seconds),
+                if_error: Duration::from_secs(config.cache_stale_if_error_seconds),
+            },
+            stale_retention,
+            capacity_bytes,
+            max_object_size: config.cache_max_object_size_kb * 1024,
+            hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            hits_by_class: Default::default(),
+            misses_by_class: Default::default(),
+            revalidations: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+        })
     }
-    
-    /// retrieves a cached response by key
-    pub async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
-        self.cache.get(key).await
+
+    /// ttl applied to responses without explicit freshness information
+    pub fn default_ttl(&self) -> Duration {
+        self.default_ttl
+    }
+
+    /// ttl for responses with this status that carry no explicit freshness
+    /// information, or `None` when they are not cached. successful responses
+    /// use the default ttl; redirects, 404/410 and origin errors have their
+    /// own (usually short) ttls, 0 disabling them. a response with explicit
+    /// freshness is cached regardless, as long as [`caches_status`] allows.
+    pub fn default_ttl_for(&self, status: StatusCode) -> Option<Duration> {
+        if !caches_status(status) {
+            return None;
+        }
+        let ttl = match status {
+            s if s.is_success() => self.default_ttl,
+            s if s.is_redirection() => self.redirect_ttl,
+            StatusCode::NOT_FOUND | StatusCode::GONE => self.not_found_ttl,
+            _ => self.error_ttl,
+        };
+        (!ttl.is_zero()).then_some(ttl)
+    }
+
+    /// stale windows for a response, applying the configured defaults where
+    /// the origin sent no rfc 5861 directives
+    pub fn stale_windows(&self, headers: &HeaderMap) -> StaleWindows {
+        StaleWindows::for_response(headers, &self.stale_defaults)
     }
-    
-    /// inserts a response into the cache
+
+    /// largest response, in bytes, that is stored
+    pub fn max_object_size(&self) -> u64 {
+        self.max_object_size
+    }
+
+    /// policy used to compose the cache key of a request
+    pub fn key_policy(&self) -> &CacheKeyPolicy {
+        &self.key_policy
+    }
+
+    /// key the response to a request is (or would be) stored under: the
+    /// variant key when the primary key is known to vary, the primary key otherwise
+    pub async fn lookup_key(&self, key: &str, request_headers: &HeaderMap) -> String {
+        match self.variants.get(key).await {
+            Some(vary) => variant_key(key, &vary, request_headers),
+            None => key.to_string(),
+        }
+    }
+
+    /// retrieves the cached response for a request, selecting the variant by
+    /// the request headers named in the stored `vary`. memory misses fall
+    /// through to the disk tier, and disk hits are promoted back into memory.
+    /// the entry may be stale, see [`CachedResponse::is_fresh`]. counts the
+    /// lookup as a hit when a fresh entry was found and as a miss otherwise.
+    pub async fn get(&self, key: &str, request_headers: &HeaderMap) -> Option<Arc<CachedResponse>> {
+        let lookup_key = self.lookup_key(key, request_headers).await;
+        let mut found = self.cache.get(&lookup_key).await;
+        if let (None, Some(disk)) = (&found, &self.disk) {
+            found = disk.get(&lookup_key).await;
+            if let Some(response) = &found {
+                self.disk_hits.fetch_add(1, Ordering::Relaxed);
+                self.cache
+                    .insert(lookup_key.clone(), response.clone())
+                    .await;
+                self.tags
+                    .lock()
+                    .unwrap()
+                    .tag(&lookup_key, &response.headers);
+            }
+        }
+        match found.as_ref().filter(|r| r.is_fresh()) {
+            Some(response) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.hits_by_class[StatusClass::of(response.status).index()]
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        found
+    }
+
+    /// inserts a response to the given request into the cache, fresh for `ttl`
     pub async fn insert(
-        &self, 
-        key: String, 
+        &self,
+        key: String,
+        request_headers: &HeaderMap,
         status: StatusCode,
         headers: HeaderMap,
-        body: Bytes
+        body: Bytes,
+        ttl: Duration,
     ) {
-        let cached_response = Arc::new(CachedResponse {
-            status,
-            headers,
-            body,
-        });
-        
-        self.cache.insert(key, cached_response).await;
+        let stale = self.stale_windows(&headers);
+        let cached_response = Arc::new(CachedResponse::new(status, headers, body, ttl, stale));
+
+        self.insert_response(key, request_headers, cached_response)
+            .await;
+    }
+
+    /// inserts an already built response into the cache. responses carrying
+    /// `vary: *` are ignored, callers are expected to have filtered them out.
+    /// responses larger than the maximum object size bypass the cache.
+    pub async fn insert_response(
+        &self,
+        key: String,
+        request_headers: &HeaderMap,
+        response: Arc<CachedResponse>,
+    ) {
+        let Some(vary) = freshness::vary_fields(&response.headers) else {
+            return;
+        };
+        if response.size_bytes() as u64 > self.max_object_size {
+            log::debug!(
+                "not caching {}: {} bytes exceeds max object size of {} bytes",
+                key,
+                response.size_bytes(),
+                self.max_object_size
+            );
+            return;
+        }
+
+        let previous = self.variants.get(&key).await;
+        let (store_key, vary) = if vary.is_empty() {
+            if previous.is_some() {
+                // the origin stopped varying, so the old variants are dead weight
+                self.invalidate(&key).await;
+            }
+            (key.clone(), Arc::new(vary))
+        } else {
+            let vary = match previous {
+                Some(previous) if *previous == vary => previous,
+                previous => {
+                    if previous.is_some() {
+                        self.invalidate(&key).await;
+                    }
+                    let vary = Arc::new(vary);
+                    self.variants.insert(key.clone(), vary.clone()).await;
+                    vary
+                }
+            };
+            // an entry stored before the origin started varying is superseded
+            self.cache.invalidate(&key).await;
+            if let Some(disk) = &self.disk {
+                disk.remove(&key).await;
+            }
+            (variant_key(&key, &vary, request_headers), vary)
+        };
+
+        self.cache.insert(store_key.clone(), response.clone()).await;
+        self.tags.lock().unwrap().tag(&store_key, &response.headers);
+
+        // persist off the request path, large bodies can take a while to sync
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            let lifetime = remaining_lifetime(&response, self.stale_retention);
+            tokio::spawn(async move {
+                disk.store(&store_key, &key, &vary, &response, lifetime)
+                    .await;
+            });
+        }
     }
-    
+
     /// returns the number of items in the cache
     pub async fn len(&self) -> u64 {
         self.cache.entry_count()
     }
-    
-    /// invalidates a specific cache entry
+
+    /// returns true when the cache holds no items
+    pub async fn is_empty(&self) -> bool {
+        self.cache.entry_count() == 0
+    }
+
+    /// records a stale entry that the origin confirmed with a `304`
+    pub fn record_revalidation(&self) {
+        self.revalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// records a response fetched from the origin for a cacheable request
+    pub fn record_origin_response(&self, status: StatusCode) {
+        self.misses_by_class[StatusClass::of(status).index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// records a stale entry served to a client
+    pub fn record_stale(&self) {
+        self.stale.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// returns the current hit/miss counters, item count and size. pending
+    /// housekeeping is flushed first so the counts reflect recent inserts.
+    pub async fn stats(&self) -> CacheStats {
+        self.cache.run_pending_tasks().await;
+        let (disk_items, disk_bytes_used) = self.disk.as_ref().map_or((0, 0), |d| d.usage());
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            revalidations: self.revalidations.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            items: self.cache.entry_count(),
+            bytes_used: self.cache.weighted_size(),
+            capacity_bytes: self.capacity_bytes,
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            disk_items,
+            disk_bytes_used,
+            hits_by_class: self
+                .hits_by_class
+                .each_ref()
+                .map(|c| c.load(Ordering::Relaxed)),
+            misses_by_class: self
+                .misses_by_class
+                .each_ref()
+                .map(|c| c.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// invalidates a specific cache entry, including all of its variants
     pub async fn invalidate(&self, key: &str) {
         self.cache.invalidate(key).await;
+        if let Some(disk) = &self.disk {
+            disk.remove(key).await;
+        }
+        if self.variants.remove(key).await.is_some() {
+            let prefix = format!("{}\n", key);
+            if let Some(disk) = &self.disk {
+                disk.remove_matching(|k| k.starts_with(&prefix)).await;
+            }
+            if let Err(e) = self
+                .cache
+                .invalidate_entries_if(move |k, _| k.starts_with(&prefix))
+            {
+                log::warn!("failed to invalidate variants of {}: {}", key, e);
+            }
+        }
     }
-    
-    /// clears the entire cache
+
+    /// clears the entire cache, on disk as well
     pub async fn clear(&self) {
         self.cache.invalidate_all();
+        self.variants.invalidate_all();
+        // invalidate_all notifies the eviction listener lazily, if at all
+        *self.tags.lock().unwrap() = TagIndex::default();
+        if let Some(disk) = &self.disk {
+            disk.clear().await;
+        }
     }
-} 
\ No newline at end of file
+
+    /// purges every entry selected by `target`, in memory and on disk, and
+    /// returns how many entries were affected.
+    ///
+    /// a soft purge keeps the entries but marks them stale: the next request
+    /// revalidates with the origin, and the entry can still be served within
+    /// its stale-while-revalidate and stale-if-error windows.
+    pub async fn purge(&self, target: &PurgeTarget, soft: bool) -> u64 {
+        let matches = |key: &str| target.matches(primary_key(key));
+        let mut keys: HashSet<String> = self
+            .cache
+            .iter()
+            .filter(|(key, _)| matches(key))
+            .map(|(key, _)| key.to_string())
+            .collect();
+        if let Some(disk) = &self.disk {
+            keys.extend(disk.keys_matching(matches));
+        }
+
+        if !soft {
+            let primaries: Vec<Arc<String>> = self
+                .variants
+                .iter()
+                .filter(|(primary, _)| target.matches(primary))
+                .map(|(primary, _)| primary)
+                .collect();
+            for primary in primaries {
+                self.variants.invalidate(primary.as_str()).await;
+            }
+        }
+        self.purge_keys(&keys, soft).await;
+        keys.len() as u64
+    }
+
+    /// purges every entry tagged with `tag` by its `surrogate-key` or
+    /// `cache-tag` header, in memory and on disk. see [`CdnCache::purge`]
+    /// for soft purges.
+    pub async fn purge_tag(&self, tag: &str, soft: bool) -> u64 {
+        let indexed = self
+            .tags
+            .lock()
+            .unwrap()
+            .keys
+            .get(tag)
+            .cloned()
+            .unwrap_or_default();
+        let mut keys = HashSet::new();
+        for key in indexed {
+            // an entry may have been replaced by one that no longer carries the tag
+            let tagged =
+                self.cache.get(&key).await.is_some_and(|response| {
+                    response_tags(&response.headers).iter().any(|t| t == tag)
+                });
+            if tagged {
+                keys.insert(key);
+            }
+        }
+        if let Some(disk) = &self.disk {
+            keys.extend(disk.keys_tagged(tag));
+        }
+
+        self.purge_keys(&keys, soft).await;
+        keys.len() as u64
+    }
+
+    async fn purge_keys(&self, keys: &HashSet<String>, soft: bool) {
+        for key in keys {
+            if soft {
+                self.mark_stale(key).await;
+            } else {
+                self.cache.invalidate(key).await;
+                if let Some(disk) = &self.disk {
+                    disk.remove(key).await;
+                }
+            }
+        }
+    }
+
+    /// ends the freshness of a stored entry now, keeping its stale windows
+    async fn mark_stale(&self, key: &str) {
+        let found = match (self.cache.get(key).await, &self.disk) {
+            (Some(response), _) => Some(response),
+            (None, Some(disk)) => disk.get(key).await,
+            (None, None) => None,
+        };
+        let Some(response) = found.filter(|response| response.is_fresh()) else {
+            return;
+        };
+
+        let mut stale = (*response).clone();
+        stale.ttl = stale.stored_at.elapsed();
+        let stale = Arc::new(stale);
+        self.cache.insert(key.to_string(), stale.clone()).await;
+        self.tags.lock().unwrap().tag(key, &stale.headers);
+        if let Some(disk) = &self.disk {
+            let primary = primary_key(key);
+            let vary = self.variants.get(primary).await.unwrap_or_default();
+            let lifetime = remaining_lifetime(&stale, self.stale_retention);
+            disk.store(key, primary, &vary, &stale, lifetime).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_any_run_or_single_character() {
+        assert!(glob_matches("/img/*.png", "/img/a.png"));
+        assert!(glob_matches("/img/*.png", "/img/sub/dir/a.png"));
+        assert!(glob_matches("/img/*", "/img/"));
+        assert!(glob_matches("/v?/api", "/v2/api"));
+        assert!(glob_matches("*a*b*", "xxaxxbxx"));
+        assert!(glob_matches("/é?", "/éü"));
+        assert!(!glob_matches("/img/*.png", "/img/a.jpg"));
+        assert!(!glob_matches("/v?/api", "/v10/api"));
+        assert!(!glob_matches("/a", "/ab"));
+        assert!(!glob_matches("", "/"));
+    }
+
+    #[test]
+    fn exact_purges_cover_every_header_and_cookie_variant() {
+        let target = PurgeTarget::Exact("/page".to_string());
+        assert!(target.matches("/page"));
+        assert!(target.matches("/page#accept-language=en"));
+        assert!(!target.matches("/page2"));
+        assert!(!target.matches("/page?query"));
+    }
+
+    #[test]
+    fn prefix_glob_and_all_purges() {
+        assert!(PurgeTarget::Prefix("/static/".to_string()).matches("/static/app.js"));
+        assert!(!PurgeTarget::Prefix("/static/".to_string()).matches("/index.html"));
+        assert!(PurgeTarget::Glob("/*.css".to_string()).matches("/a/b.css"));
+        assert!(PurgeTarget::All.matches("/anything"));
+    }
+
+    #[tokio::test]
+    async fn clearing_drops_the_tag_index() {
+        use clap::Parser;
+        let cache = CdnCache::new(&Config::parse_from(["shadowstep"]))
+            .await
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "surrogate-key",
+            header::HeaderValue::from_static("product-42"),
+        );
+        let ttl = Duration::from_secs(60);
+        cache
+            .insert(
+                "/a".to_string(),
+                &HeaderMap::new(),
+                StatusCode::OK,
+                headers,
+                Bytes::from_static(b"a"),
+                ttl,
+            )
+            .await;
+        assert!(cache.tags.lock().unwrap().keys.contains_key("product-42"));
+
+        cache.clear().await;
+        assert!(cache.tags.lock().unwrap().keys.is_empty());
+        assert_eq!(cache.purge_tag("product-42", false).await, 0);
+    }
+}
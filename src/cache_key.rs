@@ -0,0 +1,413 @@
+//! cache key composition.
+//!
+//! by default a response is cached under the request's path and query, with
+//! percent-encoding normalised. a json policy file can change that per route:
+//!
+//! ```json
+//! {
+//!   "default": { "include_host": true, "exclude_query": ["utm_*", "fbclid"], "sort_query": true },
+//!   "routes": [
+//!     { "path": "/static/*", "ignore_query": true },
+//!     { "path": "/search", "include_query": ["q", "page"], "headers": ["accept-language"], "cookies": ["currency"] }
+//!   ]
+//! }
+//! ```
+//!
+//! the first route whose `path` glob matches is used, with its options laid
+//! over `default`. keys look like `https://host/path?query#extras`, where the
+//! scheme and host only appear when included and `extras` lists the selected
+//! header and cookie values.
+
+use crate::cache::glob_matches;
+use crate::util::{Result, ShadowError};
+use http::{header, HeaderMap, HeaderName};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// separates the url part of a key from the header and cookie values
+pub const EXTRAS_SEPARATOR: char = '#';
+
+/// how the key is composed for one route
+#[derive(Clone, Debug, Default)]
+pub struct KeyRules {
+    pub include_scheme: bool,
+    pub include_host: bool,
+    /// drop the query string entirely
+    pub ignore_query: bool,
+    /// when set, only these query parameters are kept (globs allowed)
+    pub include_query: Option<Vec<String>>,
+    /// query parameters that are dropped (globs allowed)
+    pub exclude_query: Vec<String>,
+    /// order query parameters so that `?a=1&b=2` and `?b=2&a=1` share an entry
+    pub sort_query: bool,
+    /// treat paths case-insensitively
+    pub lowercase_path: bool,
+    /// request headers whose values become part of the key
+    pub headers: Vec<HeaderName>,
+    /// cookies whose values become part of the key
+    pub cookies: Vec<String>,
+}
+
+fn config_error(message: impl Into<String>) -> ShadowError {
+    ShadowError::Config(format!("cache key policy: {}", message.into()))
+}
+
+fn string_list(value: &Value, option: &str) -> Result<Vec<String>> {
+    value
+        .as_array()
+        .and_then(|items| items.iter().map(|i| i.as_str().map(str::to_string)).collect())
+        .ok_or_else(|| config_error(format!("`{}` must be a list of strings", option)))
+}
+
+impl KeyRules {
+    fn from_json(options: &Map<String, Value>) -> Result<Self> {
+        let mut rules = Self::default();
+        for (option, value) in options {
+            let flag = || {
+                value
+                    .as_bool()
+                    .ok_or_else(|| config_error(format!("`{}` must be true or false", option)))
+            };
+            match option.as_str() {
+                "include_scheme" => rules.include_scheme = flag()?,
+                "include_host" => rules.include_host = flag()?,
+                "ignore_query" => rules.ignore_query = flag()?,
+                "sort_query" => rules.sort_query = flag()?,
+                "lowercase_path" => rules.lowercase_path = flag()?,
+                "include_query" => rules.include_query = Some(string_list(value, option)?),
+                "exclude_query" => rules.exclude_query = string_list(value, option)?,
+                "headers" => {
+                    rules.headers = string_list(value, option)?
+                        .iter()
+                        .map(|name| HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()))
+                        .collect::<std::result::Result<_, _>>()?
+                }
+                "cookies" => rules.cookies = string_list(value, option)?,
+                // the route selector itself
+                "path" => {}
+                _ => return Err(config_error(format!("unknown option `{}`", option))),
+            }
+        }
+        Ok(rules)
+    }
+
+    fn keeps_param(&self, name: &str) -> bool {
+        let listed = |patterns: &[String]| patterns.iter().any(|p| glob_matches(p, name));
+        self.include_query.as_deref().is_none_or(listed) && !listed(&self.exclude_query)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    path: String,
+    rules: KeyRules,
+}
+
+/// per-route cache key rules
+#[derive(Clone, Debug, Default)]
+pub struct CacheKeyPolicy {
+    default: KeyRules,
+    routes: Vec<Route>,
+}
+
+impl CacheKeyPolicy {
+    /// reads a policy from a json file
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        let value: Value = serde_json::from_slice(&data)
+            .map_err(|e| config_error(format!("{:?} is not valid json: {}", path, e)))?;
+        Self::from_json(&value)
+    }
+
+    /// builds a policy from its json form, see the module docs
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let empty = Map::new();
+        let policy = value.as_object().ok_or_else(|| config_error("must be a json object"))?;
+        if let Some(option) = policy.keys().find(|k| *k != "default" && *k != "routes") {
+            return Err(config_error(format!("unknown section `{}`", option)));
+        }
+        let default = match policy.get("default") {
+            Some(default) => default
+                .as_object()
+                .ok_or_else(|| config_error("`default` must be an object"))?,
+            None => &empty,
+        };
+
+        let mut routes = Vec::new();
+        let declared = match policy.get("routes") {
+            Some(routes) => routes
+                .as_array()
+                .ok_or_else(|| config_error("`routes` must be a list"))?
+                .as_slice(),
+            None => &[],
+        };
+        for route in declared {
+            let route = route
+                .as_object()
+                .ok_or_else(|| config_error("every route must be an object"))?;
+            let path = route
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or_else(|| config_error("every route needs a `path`"))?;
+            // route options are laid over the defaults
+            let mut options = default.clone();
+            options.extend(route.clone());
+            routes.push(Route {
+                path: path.to_string(),
+                rules: KeyRules::from_json(&options)?,
+            });
+        }
+
+        Ok(Self {
+            default: KeyRules::from_json(default)?,
+            routes,
+        })
+    }
+
+    /// rules of the first route matching the (normalised) path
+    pub fn rules_for(&self, path: &str) -> &KeyRules {
+        self.routes
+            .iter()
+            .find(|route| glob_matches(&route.path, path))
+            .map_or(&self.default, |route| &route.rules)
+    }
+
+    /// whether every route's key includes the host
+    pub fn always_includes_host(&self) -> bool {
+        self.default.include_host && self.routes.iter().all(|route| route.rules.include_host)
+    }
+
+    /// composes the cache key of a request
+    pub fn key(&self, scheme: &str, host: &str, path_and_query: &str, headers: &HeaderMap) -> String {
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path_and_query, None),
+        };
+        let path = normalize_percent_encoding(path);
+        let rules = self.rules_for(&path);
+
+        let mut key = String::new();
+        if rules.include_scheme {
+            key.push_str(&scheme.to_ascii_lowercase());
+            key.push(':');
+        }
+        if rules.include_host {
+            key.push_str("//");
+            key.push_str(&normalize_host(host, scheme));
+        }
+        if rules.lowercase_path {
+            key.push_str(&path.to_lowercase());
+        } else {
+            key.push_str(&path);
+        }
+
+        if let (false, Some(query)) = (rules.ignore_query, query) {
+            let mut params: Vec<String> = query
+                .split('&')
+                .filter(|p| !p.is_empty())
+                .map(normalize_percent_encoding)
+                .filter(|p| rules.keeps_param(p.split('=').next().unwrap_or_default()))
+                .collect();
+            if rules.sort_query {
+                params.sort();
+            }
+            if !params.is_empty() {
+                key.push('?');
+                key.push_str(&params.join("&"));
+            }
+        }
+
+        let mut extras = Vec::new();
+        for name in &rules.headers {
+            let values: Vec<&str> = headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(str::trim)
+                .collect();
+            extras.push(format!("{}={}", name, values.join(",")));
+        }
+        for name in &rules.cookies {
+            extras.push(format!("cookie:{}={}", name, cookie(headers, name).unwrap_or_default()));
+        }
+        if !extras.is_empty() {
+            key.push(EXTRAS_SEPARATOR);
+            key.push_str(&extras.join("&"));
+        }
+        key
+    }
+
+    /// turns a url given to the purge api into the key it was cached under,
+    /// leaving out header and cookie values. absolute urls supply the scheme
+    /// and host; anything else is taken to be in key form already.
+    pub fn purge_key(&self, url: &str) -> String {
+        match url.parse::<http::Uri>() {
+            Ok(uri) if uri.scheme().is_some() => {
+                let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+                let key = self.key(
+                    uri.scheme_str().unwrap_or("http"),
+                    uri.authority().map_or("", |a| a.as_str()),
+                    path_and_query,
+                    &HeaderMap::new(),
+                );
+                match key.split_once(EXTRAS_SEPARATOR) {
+                    Some((url_part, _)) => url_part.to_string(),
+                    None => key,
+                }
+            }
+            _ => url.to_string(),
+        }
+    }
+}
+
+/// value of one cookie from the request's `cookie` headers
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value)
+}
+
+/// lowercases the host and drops the scheme's default port
+fn normalize_host(host: &str, scheme: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    let default_port = if scheme.eq_ignore_ascii_case("https") { ":443" } else { ":80" };
+    host.strip_suffix(default_port).map(str::to_string).unwrap_or(host)
+}
+
+/// rfc 3986 normalisation: percent-encoded unreserved characters are decoded
+/// and the remaining escapes use uppercase hex
+fn normalize_percent_encoding(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = String::with_capacity(input.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            // from_str_radix alone would also take a sign, as in `%+1`
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escape {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                out.push(byte as char);
+                i += 3;
+            }
+            Some(byte) => {
+                out.push_str(&format!("%{:02X}", byte));
+                i += 3;
+            }
+            None => {
+                // input is a request target, so anything else is plain ascii
+                out.push(bytes[i] as char);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use serde_json::json;
+
+    fn policy(value: Value) -> CacheKeyPolicy {
+        CacheKeyPolicy::from_json(&value).unwrap()
+    }
+
+    fn key(policy: &CacheKeyPolicy, path_and_query: &str) -> String {
+        policy.key("https", "www.example.com", path_and_query, &HeaderMap::new())
+    }
+
+    #[test]
+    fn percent_encoding_is_normalised() {
+        assert_eq!(normalize_percent_encoding("/caf%c3%a9"), "/caf%C3%A9");
+        assert_eq!(normalize_percent_encoding("/%7euser/%41%2d%5F%2E"), "/~user/A-_.");
+        // reserved characters stay encoded
+        assert_eq!(normalize_percent_encoding("/a%2fb%3Fc%20d"), "/a%2Fb%3Fc%20d");
+        // broken escapes are left alone
+        assert_eq!(normalize_percent_encoding("/100%"), "/100%");
+        assert_eq!(normalize_percent_encoding("/%4"), "/%4");
+        assert_eq!(normalize_percent_encoding("/%zz%+1"), "/%zz%+1");
+    }
+
+    #[test]
+    fn default_key_is_the_normalised_path_and_query() {
+        let policy = CacheKeyPolicy::default();
+        assert_eq!(key(&policy, "/a%7eb?q=%41&&x"), "/a~b?q=A&x");
+        assert_eq!(key(&policy, "/a?"), "/a");
+        assert_eq!(key(&policy, "/a?b=2&a=1"), "/a?b=2&a=1");
+    }
+
+    #[test]
+    fn query_parameters_can_be_sorted() {
+        let policy = policy(json!({ "default": { "sort_query": true } }));
+        assert_eq!(key(&policy, "/a?b=2&a=1&a=0"), "/a?a=0&a=1&b=2");
+        // parameters are compared once normalised
+        assert_eq!(key(&policy, "/a?%62=1&a=2"), key(&policy, "/a?a=2&b=1"));
+    }
+
+    #[test]
+    fn query_parameters_can_be_included_or_excluded() {
+        let policy = policy(json!({
+            "default": { "exclude_query": ["utm_*", "fbclid"] },
+            "routes": [
+                { "path": "/search", "include_query": ["q", "page"] },
+                { "path": "/static/*", "ignore_query": true }
+            ]
+        }));
+        assert_eq!(key(&policy, "/a?utm_source=x&id=1&fbclid=y&utm=z"), "/a?id=1&utm=z");
+        assert_eq!(key(&policy, "/a?utm_source=x"), "/a");
+        // route options are laid over the default, which still excludes
+        assert_eq!(key(&policy, "/search?page=2&sort=asc&q=cat&utm_medium=x"), "/search?page=2&q=cat");
+        assert_eq!(key(&policy, "/static/app.js?v=3"), "/static/app.js");
+    }
+
+    #[test]
+    fn host_scheme_and_case_are_optional() {
+        let policy = policy(json!({
+            "default": { "include_scheme": true, "include_host": true },
+            "routes": [{ "path": "/files/*", "lowercase_path": true }]
+        }));
+        assert_eq!(policy.key("HTTPS", "WWW.Example.com:443", "/A", &HeaderMap::new()), "https://www.example.com/A");
+        assert_eq!(policy.key("http", "www.example.com:8080", "/A", &HeaderMap::new()), "http://www.example.com:8080/A");
+        assert_eq!(key(&policy, "/files/README.md"), "https://www.example.com/files/readme.md");
+        assert!(policy.always_includes_host());
+        assert!(!CacheKeyPolicy::default().always_includes_host());
+    }
+
+    #[test]
+    fn headers_and_cookies_are_appended() {
+        let policy = policy(json!({ "default": { "headers": ["Accept-Language"], "cookies": ["currency"] } }));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static("de"));
+        headers.insert(header::COOKIE, HeaderValue::from_static("session=abc; currency=EUR"));
+        assert_eq!(policy.key("https", "h", "/p?q=1", &headers), "/p?q=1#accept-language=de&cookie:currency=EUR");
+        assert_eq!(policy.key("https", "h", "/p", &HeaderMap::new()), "/p#accept-language=&cookie:currency=");
+        // purge keys leave the extras out
+        assert_eq!(policy.purge_key("https://h/p?q=1"), "/p?q=1");
+        assert_eq!(policy.purge_key("/p?q=1"), "/p?q=1");
+    }
+
+    #[test]
+    fn invalid_policies_are_refused() {
+        for value in [
+            json!([]),
+            json!({ "defaults": {} }),
+            json!({ "default": { "include_host": "yes" } }),
+            json!({ "default": { "exclude_query": "utm_*" } }),
+            json!({ "default": { "unknown": true } }),
+            json!({ "routes": [{ "ignore_query": true }] }),
+            json!({ "routes": { "path": "/api/*" } }),
+            json!({ "routes": null }),
+        ] {
+            assert!(CacheKeyPolicy::from_json(&value).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! collapsed forwarding: concurrent requests for the same cache key share a
+//! single origin fetch instead of each going to the origin.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// role of a request that joined the coalescer for a key
+pub enum Role<T: Clone> {
+    /// no fetch was in flight: this request fetches and publishes the result
+    Leader(Leader<T>),
+    /// a fetch is in flight: wait for its result with [`Coalescer::wait`]
+    Follower(broadcast::Receiver<T>),
+}
+
+/// in-flight fetch owned by the leading request. dropping it without calling
+/// [`Leader::complete`] releases the followers, which then fetch themselves.
+/// it can outlive the request, e.g. while a streamed body is still being read.
+pub struct Leader<T: Clone> {
+    coalescer: Arc<Coalescer<T>>,
+    key: String,
+    completed: bool,
+}
+
+impl<T: Clone> Leader<T> {
+    /// publishes the fetch result to every follower
+    pub fn complete(mut self, value: T) {
+        let mut inflight = self.coalescer.inflight.lock().unwrap();
+        if let Some(sender) = inflight.remove(&self.key) {
+            // sending fails only when nobody is waiting, which is fine
+            let _ = sender.send(value);
+        }
+        self.completed = true;
+    }
+}
+
+impl<T: Clone> Drop for Leader<T> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.coalescer.inflight.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+/// deduplicates concurrent fetches per key
+pub struct Coalescer<T: Clone> {
+    inflight: Mutex<HashMap<String, broadcast::Sender<T>>>,
+    wait_timeout: Duration,
+    collapsed: AtomicU64,
+}
+
+impl<T: Clone> Coalescer<T> {
+    /// creates a coalescer whose followers wait at most `wait_timeout` for
+    /// the leader before giving up
+    pub fn new(wait_timeout: Duration) -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+            wait_timeout,
+            collapsed: AtomicU64::new(0),
+        }
+    }
+
+    /// joins the fetch for `key`, becoming its leader if none is in flight
+    pub fn join(self: &Arc<Self>, key: &str) -> Role<T> {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(sender) = inflight.get(key) {
+            return Role::Follower(sender.subscribe());
+        }
+        let (sender, _) = broadcast::channel(1);
+        inflight.insert(key.to_string(), sender);
+        Role::Leader(Leader {
+            coalescer: self.clone(),
+            key: key.to_string(),
+            completed: false,
+        })
+    }
+
+    /// waits for the leader's result. returns `None` when the wait timed out
+    /// or the leader went away without a result.
+    pub async fn wait(&self, mut receiver: broadcast::Receiver<T>) -> Option<T> {
+        match tokio::time::timeout(self.wait_timeout, receiver.recv()).await {
+            Ok(Ok(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// records a request that was answered with a leader's result
+    pub fn record_collapsed(&self) {
+        self.collapsed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// number of requests answered with another request's fetch so far
+    pub fn collapsed(&self) -> u64 {
+        self.collapsed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coalescer() -> Arc<Coalescer<u32>> {
+        Arc::new(Coalescer::new(Duration::from_millis(100)))
+    }
+
+    #[tokio::test]
+    async fn followers_receive_the_leaders_result() {
+        let coalescer = coalescer();
+        let Role::Leader(leader) = coalescer.join("/a") else {
+            panic!("first request must lead");
+        };
+        let Role::Follower(first) = coalescer.join("/a") else {
+            panic!("second request must follow");
+        };
+        let Role::Follower(second) = coalescer.join("/a") else {
+            panic!("third request must follow");
+        };
+        // other keys are fetched on their own
+        assert!(matches!(coalescer.join("/b"), Role::Leader(_)));
+
+        leader.complete(7);
+        assert_eq!(coalescer.wait(first).await, Some(7));
+        assert_eq!(coalescer.wait(second).await, Some(7));
+        // the fetch is over, the next request leads a new one
+        assert!(matches!(coalescer.join("/a"), Role::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn a_dropped_leader_releases_its_followers() {
+        let coalescer = coalescer();
+        let Role::Leader(leader) = coalescer.join("/a") else {
+            panic!("first request must lead");
+        };
+        let Role::Follower(follower) = coalescer.join("/a") else {
+            panic!("second request must follow");
+        };
+        drop(leader);
+        assert_eq!(coalescer.wait(follower).await, None);
+        assert!(matches!(coalescer.join("/a"), Role::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn followers_give_up_after_the_wait_timeout() {
+        let coalescer = coalescer();
+        let _leader = coalescer.join("/a");
+        let Role::Follower(follower) = coalescer.join("/a") else {
+            panic!("second request must follow");
+        };
+        assert_eq!(coalescer.wait(follower).await, None);
+    }
+}
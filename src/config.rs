@@ -1,16 +1,27 @@
+use crate::fetcher::OriginProtocol;
+use crate::forwarding::{Cidr, ForwardedHeaders};
+use crate::proxy_protocol::ProxyVersion;
 use clap::Parser;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Config {
-    /// upstream
+    /// upstream, used for every request unless an origins file routes it elsewhere
     #[clap(long, env = "ORIGIN_URL")]
-    pub origin_url: String,
+    pub origin_url: Option<String>,
+
+    /// json file with named origins and the routes selecting them (see routing.rs)
+    #[clap(long, env = "ORIGINS_CONFIG")]
+    pub origins_config: Option<PathBuf>,
 
     #[clap(long, env = "LISTEN_ADDR", default_value = "0.0.0.0:8080")]
     pub listen_addr: String,
 
+    /// https listen address, only bound when tls is enabled
+    #[clap(long, env = "TLS_LISTEN_ADDR", default_value = "0.0.0.0:8443")]
+    pub tls_listen_addr: String,
+
     /// asset path for serving static files
     #[clap(long, env = "ASSET_PATH", default_value = "/app/assets")]
     pub asset_path: PathBuf,
@@ -19,10 +30,204 @@ pub struct Config {
     #[clap(long, env = "CACHE_TTL_SECONDS", default_value_t = 300)]
     pub cache_ttl_seconds: u64,
 
+    /// ttl for redirects (301/302/307/308) without explicit freshness; 0 disables caching them
+    #[clap(long, env = "CACHE_REDIRECT_TTL_SECONDS", default_value_t = 60)]
+    pub cache_redirect_ttl_seconds: u64,
+
+    /// ttl for 404/410 responses without explicit freshness; 0 disables caching them
+    #[clap(long, env = "CACHE_NOT_FOUND_TTL_SECONDS", default_value_t = 10)]
+    pub cache_not_found_ttl_seconds: u64,
+
+    /// ttl for origin errors (500/502/503/504) without explicit freshness; 0 (the default) disables caching them
+    #[clap(long, env = "CACHE_ERROR_TTL_SECONDS", default_value_t = 0)]
+    pub cache_error_ttl_seconds: u64,
+
+    /// how long responses carrying validators (etag / last-modified) are kept
+    /// past freshness so they can be revalidated instead of refetched
+    #[clap(long, env = "CACHE_STALE_RETENTION_SECONDS", default_value_t = 3600)]
+    pub cache_stale_retention_seconds: u64,
+
+    /// default stale-while-revalidate window, used when the origin sends none
+    #[clap(long, env = "CACHE_STALE_WHILE_REVALIDATE_SECONDS", default_value_t = 0)]
+    pub cache_stale_while_revalidate_seconds: u64,
+
+    /// default stale-if-error window, used when the origin sends none
+    #[clap(long, env = "CACHE_STALE_IF_ERROR_SECONDS", default_value_t = 300)]
+    pub cache_stale_if_error_seconds: u64,
+
+    /// how long concurrent cache misses wait for an in-flight origin fetch of
+    /// the same key before fetching themselves; 0 disables request coalescing
+    #[clap(long, env = "COALESCE_TIMEOUT_MS", default_value_t = 10000)]
+    pub coalesce_timeout_ms: u64,
+
     /// max cache size in mb
     #[clap(long, env = "CACHE_SIZE_MB", default_value_t = 100)]
     pub cache_size_mb: u64,
 
+    /// json file with per-route cache key rules; keys are path and query when unset
+    #[clap(long, env = "CACHE_KEY_CONFIG")]
+    pub cache_key_config: Option<PathBuf>,
+
+    /// directory for the persistent on-disk cache tier; disabled when unset
+    #[clap(long, env = "DISK_CACHE_DIR")]
+    pub disk_cache_dir: Option<PathBuf>,
+
+    /// max on-disk cache size in mb
+    #[clap(long, env = "DISK_CACHE_SIZE_MB", default_value_t = 1024)]
+    pub disk_cache_size_mb: u64,
+
+    /// largest response (body plus headers, in kb) that is stored; bigger ones bypass the cache
+    #[clap(long, env = "CACHE_MAX_OBJECT_SIZE_KB", default_value_t = 10240)]
+    pub cache_max_object_size_kb: u64,
+
+    /// largest request body (in kb) forwarded to the origin; bigger ones are
+    /// refused with 413. 0 removes the limit
+    #[clap(long, env = "MAX_REQUEST_BODY_KB", default_value_t = 102400)]
+    pub max_request_body_kb: u64,
+
+    /// on a cache miss, fetch range requests from the origin in full so the
+    /// object gets cached and the range is served from it; when false, ranges
+    /// are passed through to the origin and partial responses are not cached
+    #[clap(long, env = "RANGE_FETCH_FULL", default_value_t = true, action = clap::ArgAction::Set)]
+    pub range_fetch_full: bool,
+
+    /// time allowed to connect to an origin server; 0 waits indefinitely
+    #[clap(long, env = "ORIGIN_CONNECT_TIMEOUT_MS", default_value_t = 5000)]
+    pub origin_connect_timeout_ms: u64,
+
+    /// time allowed from sending a request until the origin's response head
+    /// arrives; 0 waits indefinitely
+    #[clap(long, env = "ORIGIN_FIRST_BYTE_TIMEOUT_MS", default_value_t = 60000)]
+    pub origin_first_byte_timeout_ms: u64,
+
+    /// time allowed for the whole origin response including its body; 0 waits indefinitely
+    #[clap(long, env = "ORIGIN_TOTAL_TIMEOUT_MS", default_value_t = 0)]
+    pub origin_total_timeout_ms: u64,
+
+    /// retries of idempotent requests after connection errors or a retryable status; 0 disables
+    #[clap(long, env = "ORIGIN_RETRIES", default_value_t = 2)]
+    pub origin_retries: u32,
+
+    /// backoff before the first retry, doubled for each further one
+    #[clap(long, env = "ORIGIN_RETRY_BACKOFF_MS", default_value_t = 50)]
+    pub origin_retry_backoff_ms: u64,
+
+    /// upper bound of the retry backoff
+    #[clap(long, env = "ORIGIN_RETRY_MAX_BACKOFF_MS", default_value_t = 1000)]
+    pub origin_retry_max_backoff_ms: u64,
+
+    /// origin statuses that are retried
+    #[clap(long, env = "ORIGIN_RETRY_STATUSES", value_delimiter = ',', default_value = "502,503,504")]
+    pub origin_retry_statuses: Vec<u16>,
+
+    /// retries allowed as a percentage of origin requests
+    #[clap(long, env = "ORIGIN_RETRY_BUDGET_PERCENT", default_value_t = 20)]
+    pub origin_retry_budget_percent: u32,
+
+    /// put a circuit breaker in front of the ORIGIN_URL origin; routed
+    /// origins configure their own `circuit_breaker`
+    #[clap(long, env = "ORIGIN_CIRCUIT_BREAKER", default_value_t = false, action = clap::ArgAction::Set)]
+    pub origin_circuit_breaker: bool,
+
+    /// window over which the breaker counts request outcomes
+    #[clap(long, env = "ORIGIN_BREAKER_WINDOW_MS", default_value_t = 10000)]
+    pub origin_breaker_window_ms: u64,
+
+    /// requests needed in the window before the breaker may open
+    #[clap(long, env = "ORIGIN_BREAKER_MIN_REQUESTS", default_value_t = 20)]
+    pub origin_breaker_min_requests: u32,
+
+    /// percentage of failed requests in the window that opens the breaker
+    #[clap(long, env = "ORIGIN_BREAKER_ERROR_PERCENT", default_value_t = 50)]
+    pub origin_breaker_error_percent: u32,
+
+    /// responses slower than this count as slow; 0 ignores latency
+    #[clap(long, env = "ORIGIN_BREAKER_SLOW_CALL_MS", default_value_t = 0)]
+    pub origin_breaker_slow_call_ms: u64,
+
+    /// percentage of slow requests in the window that opens the breaker
+    #[clap(long, env = "ORIGIN_BREAKER_SLOW_CALL_PERCENT", default_value_t = 100)]
+    pub origin_breaker_slow_call_percent: u32,
+
+    /// how long an open breaker fails requests fast
+    #[clap(long, env = "ORIGIN_BREAKER_OPEN_MS", default_value_t = 30000)]
+    pub origin_breaker_open_ms: u64,
+
+    /// trial requests let through by a half-open breaker
+    #[clap(long, env = "ORIGIN_BREAKER_HALF_OPEN_REQUESTS", default_value_t = 5)]
+    pub origin_breaker_half_open_requests: u32,
+
+    /// probe the servers of the ORIGIN_URL origin at this path; routed
+    /// origins configure their own `health_check`. disabled when unset
+    #[clap(long, env = "ORIGIN_HEALTH_CHECK_PATH")]
+    pub origin_health_check_path: Option<String>,
+
+    /// time between health probes
+    #[clap(long, env = "ORIGIN_HEALTH_CHECK_INTERVAL_MS", default_value_t = 5000)]
+    pub origin_health_check_interval_ms: u64,
+
+    /// time allowed for a health probe to answer
+    #[clap(long, env = "ORIGIN_HEALTH_CHECK_TIMEOUT_MS", default_value_t = 2000)]
+    pub origin_health_check_timeout_ms: u64,
+
+    /// statuses of a passing health probe; any 2xx when unset
+    #[clap(long, env = "ORIGIN_HEALTH_CHECK_STATUSES", value_delimiter = ',')]
+    pub origin_health_check_statuses: Vec<u16>,
+
+    /// passing probes in a row that bring a server back up
+    #[clap(long, env = "ORIGIN_HEALTHY_THRESHOLD", default_value_t = 2)]
+    pub origin_healthy_threshold: u32,
+
+    /// failed probes in a row that take a server down
+    #[clap(long, env = "ORIGIN_UNHEALTHY_THRESHOLD", default_value_t = 3)]
+    pub origin_unhealthy_threshold: u32,
+
+    /// headers telling origins about the client: x-forwarded, forwarded (rfc 7239), both or none
+    #[clap(long, env = "FORWARDED_HEADERS", value_enum, default_value_t = ForwardedHeaders::XForwarded)]
+    pub forwarded_headers: ForwardedHeaders,
+
+    /// comma-separated addresses and cidr networks of proxies in front of
+    /// shadowstep, whose x-forwarded-for / forwarded headers are believed
+    #[clap(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Vec<Cidr>,
+
+    /// expect a PROXY protocol (v1 or v2) header on every connection to the
+    /// http listener, e.g. behind a tcp load balancer
+    #[clap(long, env = "PROXY_PROTOCOL", default_value_t = false, action = clap::ArgAction::Set)]
+    pub proxy_protocol: bool,
+
+    /// expect a PROXY protocol header ahead of the tls handshake on the https listener
+    #[clap(long, env = "TLS_PROXY_PROTOCOL", default_value_t = false, action = clap::ArgAction::Set)]
+    pub tls_proxy_protocol: bool,
+
+    /// also accept http/2 with prior knowledge (h2c) on the http listener;
+    /// https negotiates http/2 through alpn regardless
+    #[clap(long, env = "H2C", default_value_t = false, action = clap::ArgAction::Set)]
+    pub h2c: bool,
+
+    /// send PROXY protocol headers (v1 or v2) to origins; origins can override it
+    #[clap(long, env = "ORIGIN_PROXY_PROTOCOL", value_enum)]
+    pub origin_proxy_protocol: Option<ProxyVersion>,
+
+    /// http version spoken to origins: http1, auto (http/2 where tls
+    /// negotiates it) or http2 (prior knowledge, h2c for http origins);
+    /// origins can override it
+    #[clap(long, env = "ORIGIN_PROTOCOL", value_enum, default_value_t = OriginProtocol::Http1)]
+    pub origin_protocol: OriginProtocol,
+
+    /// close a websocket tunnel after this long without data in either
+    /// direction; 0 keeps idle tunnels open
+    #[clap(long, env = "TUNNEL_IDLE_TIMEOUT_SECONDS", default_value_t = 300)]
+    pub tunnel_idle_timeout_seconds: u64,
+
+    /// listen address of the admin api (cache purging); disabled when unset
+    #[clap(long, env = "ADMIN_LISTEN_ADDR")]
+    pub admin_listen_addr: Option<String>,
+
+    /// bearer token required by the admin api
+    #[clap(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
     /// tls cert path
     #[clap(long, env = "TLS_CERT_PATH", long = "tls-cert")]
     pub tls_cert_path: Option<PathBuf>,
@@ -40,4 +245,8 @@ impl Config {
     pub fn is_tls_enabled(&self) -> bool {
         self.tls_cert_path.is_some() && self.tls_key_path.is_some()
     }
+
+    pub fn is_admin_enabled(&self) -> bool {
+        self.admin_listen_addr.is_some()
+    }
 } 
\ No newline at end of file
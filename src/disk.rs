@@ -0,0 +1,659 @@
+//! persistent on-disk second cache tier.
+//!
+//! every entry is one file named after the sha256 of its cache key, holding a
+//! small json metadata block followed by the body. files are written to a
+//! temporary name, synced and renamed into place, so a crash never leaves a
+//! half-written entry behind; the directory is synced after the rename so the
+//! entry itself survives one too. the index is rebuilt from the files
+//! themselves on startup, which keeps it consistent with what is actually on
+//! disk.
+//!
+//! writes run in the background, so an entry is recorded as pending from the
+//! moment it is handed over until its file is in place. removing or purging
+//! the key in between cancels the write, which then discards its file instead
+//! of bringing the removed entry back.
+
+use crate::cache::{self, CachedResponse};
+use crate::freshness::StaleWindows;
+use crate::util::Result;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+/// marks the start of every entry file, bumped if the layout ever changes
+const MAGIC: &[u8; 4] = b"SSC1";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// what the index knows about one file on disk
+struct IndexEntry {
+    key: String,
+    /// surrogate keys / cache tags of the stored response
+    tags: Vec<String>,
+    size: u64,
+    expires_at: SystemTime,
+    /// position in the lru order, larger is more recently used
+    tick: u64,
+}
+
+/// a write that has not been renamed into place yet
+struct PendingWrite {
+    key: String,
+    tags: Vec<String>,
+    /// tells this write apart from later ones of the same key
+    generation: u64,
+}
+
+#[derive(Default)]
+struct DiskIndex {
+    entries: HashMap<String, IndexEntry>,
+    lru: BTreeMap<u64, String>,
+    bytes_used: u64,
+    next_tick: u64,
+    /// writes in flight by file name, only the latest one per name is kept
+    pending: HashMap<String, PendingWrite>,
+    next_generation: u64,
+}
+
+impl DiskIndex {
+    fn touch(&mut self, name: &str) {
+        let tick = self.next_tick;
+        if let Some(entry) = self.entries.get_mut(name) {
+            self.lru.remove(&entry.tick);
+            entry.tick = tick;
+            self.lru.insert(tick, name.to_string());
+            self.next_tick += 1;
+        }
+    }
+
+    fn insert(&mut self, name: String, key: String, tags: Vec<String>, size: u64, expires_at: SystemTime) {
+        self.remove(&name);
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.lru.insert(tick, name.clone());
+        self.bytes_used += size;
+        self.entries.insert(
+            name,
+            IndexEntry {
+                key,
+                tags,
+                size,
+                expires_at,
+                tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, name: &str) -> Option<IndexEntry> {
+        let entry = self.entries.remove(name)?;
+        self.lru.remove(&entry.tick);
+        self.bytes_used -= entry.size;
+        Some(entry)
+    }
+
+    /// records a write of `key` as in flight, superseding any earlier one, and
+    /// returns its generation
+    fn begin_write(&mut self, name: String, key: String, tags: Vec<String>) -> u64 {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        self.pending.insert(name, PendingWrite { key, tags, generation });
+        generation
+    }
+
+    /// true while the write of `generation` has been neither superseded nor cancelled
+    fn is_current(&self, name: &str, generation: u64) -> bool {
+        self.pending.get(name).is_some_and(|write| write.generation == generation)
+    }
+
+    /// least recently used entries to drop so that `incoming` more bytes fit
+    fn evict_for(&mut self, incoming: u64, capacity: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.bytes_used + incoming > capacity {
+            let Some((_, name)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&name) {
+                self.bytes_used -= entry.size;
+            }
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+/// vary rule found for a primary key while loading the disk tier
+pub struct LoadedVary {
+    pub primary_key: String,
+    pub vary: Vec<HeaderName>,
+}
+
+/// disk-backed lru cache of responses, bounded in bytes
+pub struct DiskCache {
+    dir: PathBuf,
+    capacity_bytes: u64,
+    index: Mutex<DiskIndex>,
+    tmp_counter: AtomicU64,
+    /// held while a finished write is renamed into place and while entries
+    /// are removed, so a removal cannot slip in between the two
+    commit: tokio::sync::Mutex<()>,
+}
+
+fn file_name(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn unix_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+fn from_unix_secs(secs: f64) -> SystemTime {
+    UNIX_EPOCH + Duration::try_from_secs_f64(secs).unwrap_or_default()
+}
+
+fn secs_field(meta: &Value, field: &str) -> Duration {
+    meta[field]
+        .as_f64()
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        .unwrap_or_default()
+}
+
+/// serialises an entry: magic, metadata length, json metadata, body
+fn encode(
+    key: &str,
+    primary_key: &str,
+    vary: &[HeaderName],
+    response: &CachedResponse,
+    expires_at: SystemTime,
+) -> Vec<u8> {
+    let stored_at = SystemTime::now() - response.stored_at.elapsed();
+    // header values that are not visible ascii cannot round-trip through json
+    // strings and are dropped; origins practically never send them
+    let headers: Vec<Value> = response
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Some(json!([name.as_str(), value.to_str().ok()?])))
+        .collect();
+    let meta = json!({
+        "key": key,
+        "primary_key": primary_key,
+        "vary": vary.iter().map(HeaderName::as_str).collect::<Vec<_>>(),
+        "status": response.status.as_u16(),
+        "headers": headers,
+        "stored_at": unix_secs(stored_at),
+        "expires_at": unix_secs(expires_at),
+        "ttl": response.ttl.as_secs_f64(),
+        "initial_age": response.initial_age.as_secs_f64(),
+        "stale_while_revalidate": response.stale.while_revalidate.as_secs_f64(),
+        "stale_if_error": response.stale.if_error.as_secs_f64(),
+    });
+    let meta = meta.to_string().into_bytes();
+
+    let mut data = Vec::with_capacity(8 + meta.len() + response.body.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    data.extend_from_slice(&meta);
+    data.extend_from_slice(&response.body);
+    data
+}
+
+/// splits an entry file into its parsed metadata and the offset of the body
+fn decode_meta(data: &[u8]) -> Option<(Value, usize)> {
+    if data.len() < 8 || &data[..4] != MAGIC {
+        return None;
+    }
+    let meta_len = u32::from_le_bytes(data[4..8].try_into().ok()?) as usize;
+    let meta = serde_json::from_slice(data.get(8..8 + meta_len)?).ok()?;
+    Some((meta, 8 + meta_len))
+}
+
+/// reads just the metadata block of an entry file
+fn read_meta(path: &Path) -> Option<Value> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut head = [0u8; 8];
+    file.read_exact(&mut head).ok()?;
+    if &head[..4] != MAGIC {
+        return None;
+    }
+    let meta_len = u32::from_le_bytes(head[4..8].try_into().ok()?) as usize;
+    let mut meta = vec![0u8; meta_len];
+    file.read_exact(&mut meta).ok()?;
+    serde_json::from_slice(&meta).ok()
+}
+
+fn meta_headers(meta: &Value) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for pair in meta["headers"].as_array()? {
+        let name = HeaderName::from_bytes(pair[0].as_str()?.as_bytes()).ok()?;
+        let value = HeaderValue::from_str(pair[1].as_str()?).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
+
+fn decode_response(meta: &Value, body: Bytes) -> Option<CachedResponse> {
+    let status = StatusCode::from_u16(u16::try_from(meta["status"].as_u64()?).ok()?).ok()?;
+    let headers = meta_headers(meta)?;
+    // translate the wall-clock store time back onto the monotonic clock
+    let stored_ago = SystemTime::now()
+        .duration_since(from_unix_secs(meta["stored_at"].as_f64()?))
+        .unwrap_or_default();
+    let stored_at = Instant::now().checked_sub(stored_ago)?;
+
+    Some(CachedResponse {
+        status,
+        headers,
+        body,
+        ttl: secs_field(meta, "ttl"),
+        stored_at,
+        initial_age: secs_field(meta, "initial_age"),
+        stale: StaleWindows {
+            while_revalidate: secs_field(meta, "stale_while_revalidate"),
+            if_error: secs_field(meta, "stale_if_error"),
+        },
+    })
+}
+
+/// writes `data` to a new file at `path` and syncs it
+async fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+/// makes the renames into `dir` durable. windows cannot open a directory for
+/// syncing, there the rename is left to the filesystem.
+async fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    tokio::fs::File::open(dir).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+impl DiskCache {
+    /// opens (creating if needed) the cache directory and rebuilds the index
+    /// from the entries found there. leftover temporary files and expired or
+    /// unreadable entries are removed. returns the vary rules of the stored
+    /// variants so the memory tier can find them again.
+    pub fn open(dir: &Path, capacity_bytes: u64) -> Result<(Self, Vec<LoadedVary>)> {
+        std::fs::create_dir_all(dir)?;
+        let now = SystemTime::now();
+
+        let mut found = Vec::new();
+        let mut vary_rules: HashMap<String, Vec<HeaderName>> = HashMap::new();
+        for dir_entry in std::fs::read_dir(dir)? {
+            let dir_entry = dir_entry?;
+            let path = dir_entry.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()).map(str::to_string) else {
+                continue;
+            };
+            if name.ends_with(TMP_SUFFIX) {
+                // an interrupted write, never renamed into place
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let meta = read_meta(&path);
+            let usable = meta.as_ref().filter(|m| {
+                m["expires_at"].as_f64().is_some_and(|e| from_unix_secs(e) > now)
+                    && m["key"].as_str().is_some_and(|k| file_name(k) == name)
+            });
+            let Some(meta) = usable else {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            };
+
+            let key = meta["key"].as_str().unwrap_or_default().to_string();
+            let vary: Vec<HeaderName> = meta["vary"]
+                .as_array()
+                .map(|fields| {
+                    fields
+                        .iter()
+                        .filter_map(|f| HeaderName::from_bytes(f.as_str()?.as_bytes()).ok())
+                        .collect()
+                })
+                .unwrap_or_default();
+            if let (false, Some(primary_key)) = (vary.is_empty(), meta["primary_key"].as_str()) {
+                vary_rules.insert(primary_key.to_string(), vary);
+            }
+            let tags = cache::response_tags(&meta_headers(meta).unwrap_or_default());
+            let size = dir_entry.metadata().map(|m| m.len()).unwrap_or(0);
+            let stored_at = meta["stored_at"].as_f64().unwrap_or(0.0);
+            let expires_at = from_unix_secs(meta["expires_at"].as_f64().unwrap_or(0.0));
+            found.push((stored_at, name, key, tags, size, expires_at));
+        }
+
+        // oldest entries first, so they are the first to be evicted
+        found.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut index = DiskIndex::default();
+        for (_, name, key, tags, size, expires_at) in found {
+            index.insert(name, key, tags, size, expires_at);
+        }
+        for name in index.evict_for(0, capacity_bytes) {
+            let _ = std::fs::remove_file(dir.join(name));
+        }
+
+        info!(
+            "disk cache at {:?}: loaded {} entries ({} bytes)",
+            dir,
+            index.entries.len(),
+            index.bytes_used
+        );
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            capacity_bytes,
+            index: Mutex::new(index),
+            tmp_counter: AtomicU64::new(0),
+            commit: tokio::sync::Mutex::new(()),
+        };
+        let vary_rules = vary_rules
+            .into_iter()
+            .map(|(primary_key, vary)| LoadedVary { primary_key, vary })
+            .collect();
+        Ok((cache, vary_rules))
+    }
+
+    /// reads an entry, returning `None` when it is absent, expired or corrupt
+    pub async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let name = file_name(key);
+        let expired = {
+            let mut index = self.index.lock().unwrap();
+            let expires_at = index.entries.get(&name)?.expires_at;
+            let expired = expires_at <= SystemTime::now();
+            if expired {
+                index.remove(&name);
+            } else {
+                index.touch(&name);
+            }
+            expired
+        };
+        if expired {
+            self.delete_file(&name).await;
+            return None;
+        }
+
+        let decoded = tokio::fs::read(self.dir.join(&name))
+            .await
+            .ok()
+            .and_then(|data| {
+                let (meta, body_offset) = decode_meta(&data)?;
+                if meta["key"].as_str() != Some(key) {
+                    return None;
+                }
+                decode_response(&meta, Bytes::from(data).slice(body_offset..))
+            });
+        match decoded {
+            Some(response) => Some(Arc::new(response)),
+            None => {
+                warn!("dropping unreadable disk cache entry for {}", key);
+                self.remove(key).await;
+                None
+            }
+        }
+    }
+
+    /// writes an entry that stays valid for `lifetime`, evicting least
+    /// recently used entries as needed to stay within capacity
+    pub async fn store(
+        &self,
+        key: &str,
+        primary_key: &str,
+        vary: &[HeaderName],
+        response: &CachedResponse,
+        lifetime: Duration,
+    ) {
+        let expires_at = SystemTime::now() + lifetime;
+        let data = encode(key, primary_key, vary, response, expires_at);
+        let size = data.len() as u64;
+        if size > self.capacity_bytes {
+            debug!("not writing {} to disk: larger than the disk cache", key);
+            return;
+        }
+
+        let name = file_name(key);
+        let tags = cache::response_tags(&response.headers);
+        let generation = self
+            .index
+            .lock()
+            .unwrap()
+            .begin_write(name.clone(), key.to_string(), tags.clone());
+
+        let counter = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = self.dir.join(format!("{}.{}{}", name, counter, TMP_SUFFIX));
+        let committed = async {
+            write_synced(&tmp_path, &data).await?;
+            let _commit = self.commit.lock().await;
+            if !self.index.lock().unwrap().is_current(&name, generation) {
+                return Ok(false);
+            }
+            tokio::fs::rename(&tmp_path, self.dir.join(&name)).await?;
+
+            let evicted = {
+                let mut index = self.index.lock().unwrap();
+                index.pending.remove(&name);
+                index.remove(&name);
+                let evicted = index.evict_for(size, self.capacity_bytes);
+                index.insert(name.clone(), key.to_string(), tags, size, expires_at);
+                evicted
+            };
+            for name in evicted {
+                self.delete_file(&name).await;
+            }
+            Ok::<_, std::io::Error>(true)
+        }
+        .await;
+
+        match committed {
+            // synced outside the commit lock, the entry is in place either way
+            Ok(true) => {
+                if let Err(e) = sync_dir(&self.dir).await {
+                    warn!("failed to sync disk cache directory after writing {}: {}", key, e);
+                }
+            }
+            Ok(false) => {
+                debug!("not writing {} to disk: removed or replaced while being written", key);
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+            }
+            Err(e) => {
+                warn!("failed to write disk cache entry for {}: {}", key, e);
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                let mut index = self.index.lock().unwrap();
+                if index.is_current(&name, generation) {
+                    index.pending.remove(&name);
+                }
+            }
+        }
+    }
+
+    async fn delete_file(&self, name: &str) {
+        if let Err(e) = tokio::fs::remove_file(self.dir.join(name)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("failed to delete disk cache file {}: {}", name, e);
+            }
+        }
+    }
+
+    /// removes one entry, cancelling a write of it still in flight
+    pub async fn remove(&self, key: &str) {
+        let name = file_name(key);
+        let _commit = self.commit.lock().await;
+        let removed = {
+            let mut index = self.index.lock().unwrap();
+            index.pending.remove(&name);
+            index.remove(&name)
+        };
+        if removed.is_some() {
+            self.delete_file(&name).await;
+        }
+    }
+
+    /// removes every entry whose key matches the predicate, cancelling
+    /// matching writes still in flight
+    pub async fn remove_matching(&self, predicate: impl Fn(&str) -> bool) {
+        let _commit = self.commit.lock().await;
+        let names: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            index.pending.retain(|_, write| !predicate(&write.key));
+            let names: Vec<String> = index
+                .entries
+                .iter()
+                .filter(|(_, entry)| predicate(&entry.key))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in &names {
+                index.remove(name);
+            }
+            names
+        };
+        for name in names {
+            self.delete_file(&name).await;
+        }
+    }
+
+    /// keys of every entry, stored or being written, whose key matches the predicate
+    pub fn keys_matching(&self, predicate: impl Fn(&str) -> bool) -> Vec<String> {
+        let index = self.index.lock().unwrap();
+        let stored = index.entries.values().map(|entry| &entry.key);
+        let pending = index.pending.values().map(|write| &write.key);
+        stored.chain(pending).filter(|key| predicate(key)).cloned().collect()
+    }
+
+    /// keys of every entry, stored or being written, tagged with `tag`
+    pub fn keys_tagged(&self, tag: &str) -> Vec<String> {
+        let index = self.index.lock().unwrap();
+        let stored = index.entries.values().map(|entry| (&entry.key, &entry.tags));
+        let pending = index.pending.values().map(|write| (&write.key, &write.tags));
+        stored
+            .chain(pending)
+            .filter(|(_, tags)| tags.iter().any(|t| t == tag))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// removes every entry
+    pub async fn clear(&self) {
+        self.remove_matching(|_| true).await;
+    }
+
+    /// number of entries and bytes currently on disk
+    pub fn usage(&self) -> (u64, u64) {
+        let index = self.index.lock().unwrap();
+        (index.entries.len() as u64, index.bytes_used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header;
+
+    /// an empty directory of its own for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shadowstep-disk-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn response(body: &'static str) -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert("surrogate-key", HeaderValue::from_static("product-42"));
+        CachedResponse::new(
+            StatusCode::OK,
+            headers,
+            Bytes::from_static(body.as_bytes()),
+            Duration::from_secs(60),
+            StaleWindows {
+                while_revalidate: Duration::from_secs(5),
+                if_error: Duration::from_secs(30),
+            },
+        )
+    }
+
+    #[test]
+    fn index_evicts_least_recently_used_first() {
+        let mut index = DiskIndex::default();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        for name in ["a", "b", "c"] {
+            index.insert(name.to_string(), name.to_string(), Vec::new(), 10, later);
+        }
+        index.touch("a");
+        assert_eq!(index.bytes_used, 30);
+        assert_eq!(index.evict_for(15, 40), vec!["b".to_string()]);
+        assert_eq!(index.evict_for(35, 40), vec!["c".to_string(), "a".to_string()]);
+        assert_eq!(index.bytes_used, 0);
+    }
+
+    #[test]
+    fn later_and_cancelled_writes_are_not_current() {
+        let mut index = DiskIndex::default();
+        let first = index.begin_write("n".to_string(), "k".to_string(), Vec::new());
+        let second = index.begin_write("n".to_string(), "k".to_string(), Vec::new());
+        assert!(!index.is_current("n", first));
+        assert!(index.is_current("n", second));
+        index.pending.remove("n");
+        assert!(!index.is_current("n", second));
+    }
+
+    #[test]
+    fn entries_round_trip_through_their_encoding() {
+        let stored = response("hello");
+        let vary = [HeaderName::from_static("accept-encoding")];
+        let data = encode("/a\naccept-encoding:gzip", "/a", &vary, &stored, SystemTime::now());
+        let (meta, offset) = decode_meta(&data).unwrap();
+        assert_eq!(meta["key"], "/a\naccept-encoding:gzip");
+        assert_eq!(meta["primary_key"], "/a");
+        assert_eq!(meta["vary"], json!(["accept-encoding"]));
+
+        let decoded = decode_response(&meta, Bytes::from(data.clone()).slice(offset..)).unwrap();
+        assert_eq!(decoded.status, StatusCode::OK);
+        assert_eq!(decoded.body, "hello");
+        assert_eq!(decoded.headers, stored.headers);
+        assert_eq!(decoded.ttl, stored.ttl);
+        assert_eq!(decoded.stale.if_error, Duration::from_secs(30));
+
+        assert!(decode_meta(b"XXXX\0\0\0\0").is_none());
+        assert!(decode_meta(&data[..10]).is_none());
+    }
+
+    #[tokio::test]
+    async fn stored_entries_survive_a_restart() {
+        let dir = test_dir("restart");
+        let (disk, _) = DiskCache::open(&dir, 1 << 20).unwrap();
+        disk.store("/a", "/a", &[], &response("hello"), Duration::from_secs(60)).await;
+        disk.store("/b", "/b", &[], &response("gone"), Duration::ZERO).await;
+        assert_eq!(disk.get("/a").await.unwrap().body, "hello");
+        assert_eq!(disk.keys_tagged("product-42").len(), 2);
+        drop(disk);
+
+        let (disk, _) = DiskCache::open(&dir, 1 << 20).unwrap();
+        assert_eq!(disk.usage().0, 1);
+        assert_eq!(disk.get("/a").await.unwrap().body, "hello");
+        assert!(disk.get("/b").await.is_none());
+        disk.remove("/a").await;
+        assert!(disk.get("/a").await.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn purged_keys_are_removed() {
+        let dir = test_dir("purge");
+        let (disk, _) = DiskCache::open(&dir, 1 << 20).unwrap();
+        for key in ["/img/a.png", "/img/b.png", "/index.html"] {
+            disk.store(key, key, &[], &response("x"), Duration::from_secs(60)).await;
+        }
+        disk.remove_matching(|key| key.starts_with("/img/")).await;
+        assert_eq!(disk.keys_matching(|_| true), vec!["/index.html".to_string()]);
+        disk.clear().await;
+        assert_eq!(disk.usage(), (0, 0));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
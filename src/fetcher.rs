@@ -1,40 +1,231 @@
+use crate::breaker::CircuitBreaker;
 use crate::config::Config;
+use crate::forwarding;
+use crate::pool::{OriginPool, Upstream};
+use crate::proxy_protocol::{self, ProxyAddresses, ProxyConnector, ProxyVersion};
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::tunnel::UpgradeProtocol;
 use crate::util::{Result, ShadowError};
-use bytes::Bytes;
-use http::{Request, Response, StatusCode, Uri};
+use futures_util::{stream, StreamExt};
+use hyper::body::HttpBody;
+use http::{Request, Response, StatusCode, Uri, Version};
 use hyper::client::HttpConnector;
-use hyper::{body::to_bytes, Body, Client as HyperClient};
+use hyper::{Body, Client as HyperClient};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// limits on how long an origin may take, `None` waits indefinitely
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OriginTimeouts {
+    /// establishing the tcp (and tls) connection
+    pub connect: Option<Duration>,
+    /// from sending the request until the response head arrives
+    pub first_byte: Option<Duration>,
+    /// the whole exchange including all retries and the response body
+    pub total: Option<Duration>,
+}
+
+impl OriginTimeouts {
+    /// the timeouts given on the command line
+    pub fn from_config(config: &Config) -> Self {
+        let limit = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+        Self {
+            connect: limit(config.origin_connect_timeout_ms),
+            first_byte: limit(config.origin_first_byte_timeout_ms),
+            total: limit(config.origin_total_timeout_ms),
+        }
+    }
+}
+
+/// http version spoken to an origin
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OriginProtocol {
+    /// http/1.1 only
+    Http1,
+    /// http/2 when the tls handshake negotiates it through alpn, otherwise
+    /// http/1.1; plain http origins get http/1.1
+    Auto,
+    /// http/2 only, with prior knowledge: h2c for plain http origins
+    Http2,
+}
+
+impl OriginProtocol {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "http1" => Ok(Self::Http1),
+            "auto" => Ok(Self::Auto),
+            "http2" => Ok(Self::Http2),
+            other => Err(ShadowError::Config(format!(
+                "unknown origin protocol `{}`, expected http1, auto or http2",
+                other
+            ))),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct OriginFetcher {
-    client: Arc<HyperClient<HttpConnector>>,
-    origin_base_url: Arc<url::Url>,
+    client: Arc<HyperClient<HttpsConnector<ProxyConnector>>>,
+    pool: Arc<OriginPool>,
+    timeouts: OriginTimeouts,
+    retry: RetryPolicy,
+    budget: Arc<RetryBudget>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+/// true when a hyper error was caused by the connect timeout running out
+fn is_connect_timeout(error: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return io.kind() == std::io::ErrorKind::TimedOut;
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// true when a request failed on its own body, which the client aborted or
+/// which was cut off at the body limit, rather than because of the origin
+fn is_client_error(error: &ShadowError) -> bool {
+    matches!(error, ShadowError::Hyper(e) if e.is_user())
+}
+
+/// a copy of the request head with a new body, for retries
+fn rebuild(parts: &http::request::Parts, body: Body) -> Request<Body> {
+    let mut request = Request::new(body);
+    if let Some(addresses) = parts.extensions.get::<ProxyAddresses>() {
+        request.extensions_mut().insert(*addresses);
+    }
+    if let Some(protocol) = parts.extensions.get::<UpgradeProtocol>() {
+        request.extensions_mut().insert(protocol.clone());
+    }
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    request
 }
 
 impl OriginFetcher {
-    pub fn new(config: &Config) -> Result<Self> {
+    pub fn new(
+        pool: OriginPool,
+        timeouts: OriginTimeouts,
+        retry: RetryPolicy,
+        breaker: Option<CircuitBreaker>,
+        proxy_protocol: Option<ProxyVersion>,
+        protocol: OriginProtocol,
+    ) -> Result<Self> {
+        if proxy_protocol.is_some() && protocol != OriginProtocol::Http1 {
+            // a connection's PROXY header names one client, while http/2 multiplexes many
+            return Err(ShadowError::Config(
+                "PROXY protocol headers cannot be sent on http/2 origin connections".into(),
+            ));
+        }
         let mut http_connector = HttpConnector::new();
-        http_connector.enforce_http(false); // this configuration allows both http and https schemes.
-        
-        let client = Arc::new(
-            HyperClient::builder()
-                .build(http_connector),
-        );
-        let origin_base_url = Arc::new(
-            url::Url::parse(&config.origin_url)
-                .map_err(ShadowError::UrlParse)?,
-        );
+        http_connector.enforce_http(false);
+        http_connector.set_connect_timeout(timeouts.connect);
+        let connector = ProxyConnector::new(http_connector, proxy_protocol);
+
+        // https connector with native-trust roots; plain http origins are still allowed.
+        // alpn offers the versions the origin may be spoken to with.
+        let builder = HttpsConnectorBuilder::new().with_native_roots().https_or_http();
+        let https_connector = match protocol {
+            OriginProtocol::Http1 => builder.enable_http1().wrap_connector(connector),
+            OriginProtocol::Auto => builder.enable_all_versions().wrap_connector(connector),
+            OriginProtocol::Http2 => builder.enable_http2().wrap_connector(connector),
+        };
+
+        let mut client_builder = HyperClient::builder();
+        if proxy_protocol.is_some() {
+            // a connection's PROXY header names one client, so it cannot be reused for others
+            client_builder.pool_max_idle_per_host(0);
+        }
+        if protocol == OriginProtocol::Http2 {
+            // every request is multiplexed onto one connection per server
+            client_builder.http2_only(true);
+        }
+        let client = Arc::new(client_builder.build(https_connector));
         Ok(Self {
             client,
-            origin_base_url,
+            pool: Arc::new(pool),
+            timeouts,
+            budget: Arc::new(RetryBudget::new(retry.budget_ratio)),
+            retry,
+            breaker: breaker.map(Arc::new),
         })
     }
 
+    pub fn pool(&self) -> &OriginPool {
+        &self.pool
+    }
+
+    pub fn breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_deref()
+    }
+
+    /// sends the request to a server of the origin's pool and returns as soon
+    /// as the response head arrives; the body is streamed by the caller.
+    /// `key` is the request's cache key, which consistent hashing balances by.
+    /// idempotent requests without a body are retried as the retry policy
+    /// allows, each time on a newly selected server.
     pub async fn fetch_from_origin(
         &self,
-        mut req: Request<Body>, // represents the incoming request to the cdn.
-    ) -> Result<Response<Bytes>> {
+        req: Request<Body>, // represents the incoming request to the cdn.
+        key: &str,
+    ) -> Result<Response<Body>> {
+        let deadline = self.timeouts.total.map(|total| (Instant::now() + total, total));
+        let (parts, body) = req.into_parts();
+        // a streamed body can only be sent once
+        let replayable = body.is_end_stream() && RetryPolicy::is_idempotent(&parts.method);
+        self.budget.record_request();
+
+        let mut body = Some(body);
+        let mut retries = 0;
+        loop {
+            let request = rebuild(&parts, body.take().unwrap_or_else(Body::empty));
+            let result = self.attempt(request, key, deadline).await;
+            let retryable = match &result {
+                Ok(response) => self.retry.retries_status(response.status()),
+                Err(ShadowError::Hyper(e)) => e.is_connect(),
+                Err(ShadowError::ConnectTimeout(_)) => true,
+                Err(_) => false,
+            };
+            if !retryable || !replayable || retries >= self.retry.attempts {
+                return result;
+            }
+            retries += 1;
+            let delay = self.retry.backoff(retries);
+            if deadline.is_some_and(|(deadline, _)| Instant::now() + delay >= deadline) {
+                return result;
+            }
+            if !self.budget.try_retry() {
+                log::debug!("retry budget of the origin is spent, not retrying {}", parts.uri);
+                return result;
+            }
+            match &result {
+                Ok(response) => log::debug!("origin answered {} for {}, retry {} in {:?}", response.status(), parts.uri, retries, delay),
+                Err(e) => log::debug!("origin request for {} failed ({}), retry {} in {:?}", parts.uri, e, retries, delay),
+            }
+            drop(result);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// one attempt at a request, on one server
+    async fn attempt(
+        &self,
+        mut req: Request<Body>,
+        key: &str,
+        deadline: Option<(Instant, Duration)>,
+    ) -> Result<Response<Body>> {
+        // an open breaker fails fast without picking a server
+        let permit = match &self.breaker {
+            Some(breaker) => Some(breaker.acquire().map_err(ShadowError::CircuitOpen)?),
+            None => None,
+        };
+        let in_flight = self.pool.select(key);
         // construct the target uri for forwarding the request to the origin server.
         let path_and_query = req
             .uri()
@@ -43,8 +234,9 @@ impl OriginFetcher {
             .unwrap_or("/");
 
         // resolve the request's path (which might be relative) against the base origin url.
-        let target_url = self
-            .origin_base_url
+        let target_url = in_flight
+            .upstream()
+            .url
             .join(path_and_query) // url::join correctly handles relative paths and base urls.
             .map_err(ShadowError::UrlParse)?;
         
@@ -53,15 +245,118 @@ impl OriginFetcher {
         // update the request's uri to the target origin; hyper will set the host header accordingly.
         *req.uri_mut() = target_uri;
         req.headers_mut().remove(http::header::HOST); // remove original host; hyper sets it from the uri.
+        forwarding::strip_hop_by_hop(req.headers_mut());
+        // a websocket handshake keeps asking for the upgrade
+        if let Some(UpgradeProtocol(protocol)) = req.extensions().get::<UpgradeProtocol>().cloned() {
+            let headers = req.headers_mut();
+            headers.insert(http::header::CONNECTION, http::HeaderValue::from_static("upgrade"));
+            headers.insert(http::header::UPGRADE, protocol);
+        }
+        let version = req.version();
+        forwarding::append_via(req.headers_mut(), version);
+        // the client may have spoken h2 to us, which says nothing about the origin
+        // connection; hyper rejects an h2 request on an http/1 connection, but
+        // sends any request as h2 on an http/2 one.
+        *req.version_mut() = Version::HTTP_11;
 
         log::debug!("fetching from origin: {}", req.uri());
 
-        let origin_response = self.client.request(req).await.map_err(ShadowError::Hyper)?;
+        // the response head has to arrive within the first-byte timeout and
+        // whatever is left of the total one
+        let (head_timeout, total) = match (self.timeouts.first_byte, deadline) {
+            (first_byte, Some((deadline, total))) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match first_byte {
+                    Some(first_byte) if first_byte < remaining => (Some(first_byte), None),
+                    _ if remaining.is_zero() => return Err(ShadowError::OriginTimeout(total)),
+                    _ => (Some(remaining), Some(total)),
+                }
+            }
+            (first_byte, None) => (first_byte, None),
+        };
+        // the client connection, for origins that are told about it with a PROXY header
+        let addresses = req.extensions().get::<ProxyAddresses>().copied();
+        let response = proxy_protocol::with_addresses(addresses, self.send(req, head_timeout)).await;
+        let response = match (response, total) {
+            // report the configured limit rather than what was left of it
+            (Err(ShadowError::OriginTimeout(_)), Some(total)) => Err(ShadowError::OriginTimeout(total)),
+            (response, _) => response,
+        };
+        // a request body the client aborted, or that outgrew the body limit,
+        // says nothing about the server
+        let client_error = response.as_ref().is_err_and(is_client_error);
+        // connection failures, timeouts and gateway errors count towards
+        // ejecting the server
+        let failed = match &response {
+            Ok(response) => matches!(
+                response.status(),
+                StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+            ),
+            Err(ShadowError::Hyper(e)) => e.is_connect(),
+            Err(ShadowError::ConnectTimeout(_) | ShadowError::OriginTimeout(_)) => true,
+            Err(_) => false,
+        };
+        if !client_error {
+            in_flight.upstream().record_request(!failed, self.pool.outliers());
+        }
+        // the permit of a client error is released without an outcome
+        if let Some(permit) = permit.filter(|_| !client_error) {
+            let server_error = response.as_ref().map_or(true, |r| r.status().is_server_error());
+            permit.record(!server_error);
+        }
+        let mut response = response?;
+        forwarding::strip_hop_by_hop(response.headers_mut());
+        let version = response.version();
+        forwarding::append_via(response.headers_mut(), version);
 
-        let (parts, body) = origin_response.into_parts();
-        let body_bytes = to_bytes(body).await.map_err(ShadowError::Hyper)?;
+        // the request counts against its server until the body is read, which
+        // has to happen before the total timeout runs out
+        Ok(response.map(|body| {
+            let body = stream::unfold(Some((body, in_flight)), move |state| async move {
+                let (mut body, in_flight) = state?;
+                let chunk = match deadline {
+                    Some((deadline, total)) => match tokio::time::timeout_at(deadline.into(), body.next()).await {
+                        Ok(chunk) => chunk,
+                        Err(_) => {
+                            log::warn!("origin response body exceeded the total timeout of {:?}", total);
+                            let timed_out = std::io::Error::new(std::io::ErrorKind::TimedOut, "origin total timeout");
+                            return Some((Err(timed_out.into()), None));
+                        }
+                    },
+                    None => body.next().await,
+                };
+                match chunk? {
+                    Ok(chunk) => Some((Ok(chunk), Some((body, in_flight)))),
+                    Err(e) => Some((Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>), None)),
+                }
+            });
+            Body::wrap_stream(body)
+        }))
+    }
+
+    /// sends a health probe for `path` to one server of the pool and returns
+    /// the status it answered with
+    pub async fn probe(&self, upstream: &Upstream, path: &str, timeout: Duration) -> Result<StatusCode> {
+        let uri: Uri = upstream.url.join(path)?.as_str().parse()?;
+        let request = Request::get(uri)
+            .header(http::header::USER_AGENT, "shadowstep-health-check")
+            .body(Body::empty())?;
+        // the body is not needed, dropping it just closes the connection
+        Ok(self.send(request, Some(timeout)).await?.status())
+    }
 
-        Ok(Response::from_parts(parts, body_bytes))
+    async fn send(&self, req: Request<Body>, timeout: Option<Duration>) -> Result<Response<Body>> {
+        let response = self.client.request(req);
+        let response = match timeout {
+            Some(limit) => tokio::time::timeout(limit, response)
+                .await
+                .map_err(|_| ShadowError::OriginTimeout(limit))?,
+            None => response.await,
+        };
+        response.map_err(|e| match self.timeouts.connect {
+            Some(limit) if is_connect_timeout(&e) => ShadowError::ConnectTimeout(limit),
+            _ => ShadowError::Hyper(e),
+        })
     }
 } 
  
\ No newline at end of file
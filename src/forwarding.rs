@@ -0,0 +1,416 @@
+//! headers of a proxy hop.
+//!
+//! hop-by-hop headers (rfc 9110, section 7.6.1) only concern a single
+//! connection: a fixed set plus every header the `Connection` header names.
+//! they are removed from requests to the origin and from its responses, and
+//! both directions get a `Via` entry.
+//!
+//! the origin learns about the client from `X-Forwarded-For/-Proto/-Host`,
+//! the standard `Forwarded` header (rfc 7239), both or neither. values in
+//! these headers are only kept, and extended with this hop, when the peer
+//! sending them is a trusted proxy; otherwise they are replaced.
+//!
+//! behind trusted proxies the client is found by walking the
+//! `X-Forwarded-For` chain (or `Forwarded`) from the right, skipping trusted
+//! addresses: the first untrusted one is the client. this is the address
+//! access logs show. likewise only a trusted proxy can set the scheme and
+//! host that cache keys are built from.
+
+use crate::config::Config;
+use actix_web::middleware::Logger;
+use actix_web::HttpRequest;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::Version;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// how this proxy names itself in `Via`
+pub const VIA_PSEUDONYM: &str = "shadowstep";
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// which headers describe the client to the origin
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ForwardedHeaders {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    XForwarded,
+    /// `Forwarded` as of rfc 7239
+    Forwarded,
+    Both,
+    None,
+}
+
+/// a network in cidr notation (`10.0.0.0/8`, `fd00::/8`); a bare address
+/// stands for itself alone
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{}` is not an ip address or cidr network", s);
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// an address of a forwarding chain, which may carry a port; `None` for
+/// `unknown`, obfuscated identifiers and garbage
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>())
+        .ok()
+}
+
+/// the addresses of `X-Forwarded-For`, or of the `for` parameters of
+/// `Forwarded` when there is none, from the client towards this hop
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &HeaderName| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::to_string)
+            .collect()
+    };
+    let forwarded_for = values(&X_FORWARDED_FOR);
+    if !forwarded_for.is_empty() {
+        return forwarded_for.iter().map(|node| parse_node(node)).collect();
+    }
+    values(&header::FORWARDED)
+        .iter()
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node))
+        })
+        .collect()
+}
+
+/// hop-by-hop headers that are never forwarded, whether `Connection` names
+/// them or not
+pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+    matches!(
+        *name,
+        header::CONNECTION
+            | header::PROXY_AUTHENTICATE
+            | header::PROXY_AUTHORIZATION
+            | header::TE
+            | header::TRAILER
+            | header::TRANSFER_ENCODING
+            | header::UPGRADE
+    ) || name == "keep-alive"
+        || name == "proxy-connection"
+}
+
+/// removes the headers listed in `Connection`, then the fixed hop-by-hop set
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    let fixed: Vec<HeaderName> = headers.keys().filter(|name| is_hop_by_hop(name)).cloned().collect();
+    for name in fixed {
+        headers.remove(name);
+    }
+}
+
+/// adds this hop to `Via`; `version` is the protocol the message was received with
+pub fn append_via(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    append_list(headers, header::VIA, &format!("{} {}", protocol, VIA_PSEUDONYM));
+}
+
+/// appends to a comma-separated header, folding earlier fields into one
+fn append_list(headers: &mut HeaderMap, name: HeaderName, item: &str) {
+    let existing: Vec<&str> = headers.get_all(&name).iter().filter_map(|v| v.to_str().ok()).collect();
+    let value = if existing.is_empty() {
+        item.to_string()
+    } else {
+        format!("{}, {}", existing.join(", "), item)
+    };
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+/// a `Forwarded` parameter value, quoted unless it is a plain token
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// how clients are identified and what is told to the origin about them
+#[derive(Clone, Debug)]
+pub struct Forwarding {
+    pub headers: ForwardedHeaders,
+    /// proxies whose forwarding headers are believed
+    trusted_proxies: Arc<[Cidr]>,
+}
+
+impl Forwarding {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            headers: config.forwarded_headers,
+            trusted_proxies: config.trusted_proxies.clone().into(),
+        }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// the client's address: the peer, or when the peer is a trusted proxy
+    /// the rightmost untrusted address of the forwarding chain. if the chain
+    /// holds something unparseable, the last trusted hop before it is as far
+    /// as can be seen.
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = peer?.to_canonical();
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+        for hop in forwarded_chain(headers).into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Some(client)
+    }
+
+    /// the client's address of an incoming request
+    pub fn request_client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let mut headers = HeaderMap::new();
+        for name in [&X_FORWARDED_FOR, &header::FORWARDED] {
+            for value in req.headers().get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        self.client_ip(req.peer_addr().map(|addr| addr.ip()), &headers)
+    }
+
+    /// access logger in actix' default format, with the resolved client
+    /// instead of the peer (whose `%a` believes any forwarding header)
+    pub fn access_logger(&self) -> Logger {
+        let forwarding = self.clone();
+        Logger::new(r#"%{client}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#).custom_request_replace(
+            "client",
+            move |req| {
+                forwarding
+                    .request_client_ip(req.request())
+                    .map_or_else(|| "-".to_string(), |ip| ip.to_string())
+            },
+        )
+    }
+
+    /// sets the forwarding headers of a request to the origin. `peer` is the
+    /// address the request came from, `proto` and `host` how it reached us.
+    /// the peer is appended to the chains it sent if it is a trusted proxy,
+    /// otherwise its forwarding headers are replaced.
+    pub fn apply(&self, headers: &mut HeaderMap, peer: Option<IpAddr>, proto: &str, host: &str) {
+        let peer = peer.map(|peer| peer.to_canonical());
+        if !peer.is_some_and(|peer| self.is_trusted(peer)) {
+            for name in [&X_FORWARDED_FOR, &X_FORWARDED_PROTO, &X_FORWARDED_HOST, &header::FORWARDED] {
+                headers.remove(name);
+            }
+        }
+        let x_forwarded = matches!(self.headers, ForwardedHeaders::XForwarded | ForwardedHeaders::Both);
+        let forwarded = matches!(self.headers, ForwardedHeaders::Forwarded | ForwardedHeaders::Both);
+
+        if x_forwarded {
+            if let Some(peer) = peer {
+                append_list(headers, X_FORWARDED_FOR.clone(), &peer.to_string());
+            }
+            // a trusted proxy in front saw the original request, so its
+            // description wins over ours
+            for (name, value) in [(&X_FORWARDED_PROTO, proto), (&X_FORWARDED_HOST, host)] {
+                if !headers.contains_key(name) {
+                    if let Ok(value) = HeaderValue::from_str(value) {
+                        headers.insert(name.clone(), value);
+                    }
+                }
+            }
+        }
+        if forwarded {
+            let node = match peer {
+                Some(IpAddr::V4(ip)) => ip.to_string(),
+                Some(IpAddr::V6(ip)) => format!("[{}]", ip),
+                None => "unknown".to_string(),
+            };
+            let mut element = format!("for={};proto={}", forwarded_value(&node), forwarded_value(proto));
+            if !host.is_empty() {
+                element.push_str(&format!(";host={}", forwarded_value(host)));
+            }
+            append_list(headers, header::FORWARDED, &element);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarding(trusted: &[&str]) -> Forwarding {
+        Forwarding {
+            headers: ForwardedHeaders::XForwarded,
+            trusted_proxies: trusted.iter().map(|cidr| cidr.parse().unwrap()).collect(),
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr = |s: &str| s.parse::<Cidr>().unwrap();
+        let contains = |network: &str, address: &str| cidr(network).contains(address.parse().unwrap());
+        assert!(contains("10.0.0.0/8", "10.255.1.2"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.0.2.7", "192.0.2.7"));
+        assert!(!contains("192.0.2.7", "192.0.2.8"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("::/0", "::1"));
+        // ipv4 clients of a dual-stack listener
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        // families never match each other
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "10.1.2.3"));
+
+        for invalid in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "example.com", "10.0.0.0/x"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+        assert!(" 10.0.0.0/8 ".parse::<Cidr>().is_ok());
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        let trusting = forwarding(&["10.0.0.0/8"]);
+        assert_eq!(trusting.client_ip(ip("203.0.113.7"), &spoofed), ip("203.0.113.7"));
+        assert_eq!(trusting.client_ip(None, &spoofed), None);
+        // nobody is trusted by default
+        assert_eq!(forwarding(&[]).client_ip(ip("10.1.2.3"), &spoofed), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn client_is_the_rightmost_untrusted_hop() {
+        let forwarding = forwarding(&["10.0.0.0/8", "192.0.2.1"]);
+        let client = |chain: &[(&str, &str)]| forwarding.client_ip(ip("10.0.0.1"), &headers(chain));
+        // whatever the client put in front of its own address is skipped
+        assert_eq!(client(&[("x-forwarded-for", "1.2.3.4, 198.51.100.9, 10.9.9.9")]), ip("198.51.100.9"));
+        assert_eq!(client(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-for", "198.51.100.9, 192.0.2.1")]), ip("198.51.100.9"));
+        // a chain of trusted proxies only ends at its first hop
+        assert_eq!(client(&[("x-forwarded-for", "10.1.1.1, 10.2.2.2")]), ip("10.1.1.1"));
+        assert_eq!(client(&[]), ip("10.0.0.1"));
+        // ports and brackets
+        assert_eq!(client(&[("x-forwarded-for", "198.51.100.9:4711")]), ip("198.51.100.9"));
+        assert_eq!(client(&[("x-forwarded-for", "[2001:db8::1]:4711")]), ip("2001:db8::1"));
+        // a trusted peer connecting over a dual-stack listener
+        assert_eq!(
+            forwarding.client_ip(ip("::ffff:10.0.0.1"), &headers(&[("x-forwarded-for", "198.51.100.9")])),
+            ip("198.51.100.9")
+        );
+    }
+
+    #[test]
+    fn unknown_hops_end_the_chain() {
+        let forwarding = forwarding(&["10.0.0.0/8"]);
+        let client = |chain: &[(&str, &str)]| forwarding.client_ip(ip("10.0.0.1"), &headers(chain));
+        // nothing left of an unknown hop can be believed
+        assert_eq!(client(&[("x-forwarded-for", "198.51.100.9, unknown, 10.2.2.2")]), ip("10.2.2.2"));
+        assert_eq!(client(&[("x-forwarded-for", "198.51.100.9, garbage")]), ip("10.0.0.1"));
+        assert_eq!(
+            client(&[("forwarded", "for=198.51.100.9, for=_hidden;proto=https, for=10.2.2.2")]),
+            ip("10.2.2.2")
+        );
+    }
+
+    #[test]
+    fn forwarded_is_used_without_x_forwarded_for() {
+        let forwarding = forwarding(&["10.0.0.0/8"]);
+        let client = |chain: &[(&str, &str)]| forwarding.client_ip(ip("10.0.0.1"), &headers(chain));
+        assert_eq!(
+            client(&[("forwarded", "for=1.2.3.4, for=\"[2001:db8::1]:4711\";proto=https, for=10.2.2.2")]),
+            ip("2001:db8::1")
+        );
+        assert_eq!(client(&[("forwarded", "proto=https;For=198.51.100.9")]), ip("198.51.100.9"));
+        // x-forwarded-for wins when both are present
+        assert_eq!(
+            client(&[("forwarded", "for=198.51.100.9"), ("x-forwarded-for", "203.0.113.7")]),
+            ip("203.0.113.7")
+        );
+    }
+}
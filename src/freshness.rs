@@ -0,0 +1,361 @@
+//! freshness rules (rfc 9111) used to decide whether an origin response may be
+//! stored in a shared cache, and for how long it stays fresh once stored.
+
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::time::{Duration, SystemTime};
+
+/// parsed `cache-control` directives relevant to a shared cache
+#[derive(Clone, Debug, Default)]
+pub struct CacheControl {
+    pub public: bool,
+    pub private: bool,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    /// rfc 5861 extensions
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    /// parses every `cache-control` header in the map
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self::from_values(headers.get_all(header::CACHE_CONTROL))
+    }
+
+    /// parses a set of `cache-control` header values, merging the directives.
+    /// unknown directives are ignored, as are malformed delta-seconds.
+    pub fn from_values<'a>(values: impl IntoIterator<Item = &'a HeaderValue>) -> Self {
+        let mut cc = Self::default();
+        for value in values {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for directive in value.split(',') {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                let seconds = arg.and_then(|a| a.parse::<u64>().ok());
+                match name.to_ascii_lowercase().as_str() {
+                    "public" => cc.public = true,
+                    // qualified forms (`private="set-cookie"`) are treated as unqualified,
+                    // which is the conservative reading for a shared cache
+                    "private" => cc.private = true,
+                    "no-store" => cc.no_store = true,
+                    "no-cache" => cc.no_cache = true,
+                    "must-revalidate" => cc.must_revalidate = true,
+                    "proxy-revalidate" => cc.proxy_revalidate = true,
+                    // when a directive is repeated, the most restrictive value wins
+                    "max-age" => cc.max_age = min_option(cc.max_age, seconds),
+                    "s-maxage" => cc.s_maxage = min_option(cc.s_maxage, seconds),
+                    "stale-while-revalidate" => {
+                        cc.stale_while_revalidate = min_option(cc.stale_while_revalidate, seconds)
+                    }
+                    "stale-if-error" => cc.stale_if_error = min_option(cc.stale_if_error, seconds),
+                    _ => {}
+                }
+            }
+        }
+        cc
+    }
+}
+
+/// how long past freshness a stored response may still be served stale
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaleWindows {
+    /// served immediately while a background refresh runs
+    pub while_revalidate: Duration,
+    /// served instead of an origin error
+    pub if_error: Duration,
+}
+
+impl StaleWindows {
+    /// windows for a response: its own rfc 5861 directives, falling back to
+    /// `defaults`. responses that demand revalidation only get the windows
+    /// they explicitly ask for.
+    pub fn for_response(headers: &HeaderMap, defaults: &StaleWindows) -> Self {
+        let cc = CacheControl::from_headers(headers);
+        let defaults = if cc.must_revalidate || cc.proxy_revalidate || cc.no_cache {
+            StaleWindows::default()
+        } else {
+            *defaults
+        };
+        // a no-cache response must be validated before every use, which
+        // serving it while refreshing in the background would skip
+        let while_revalidate = if cc.no_cache { None } else { cc.stale_while_revalidate };
+        Self {
+            while_revalidate: while_revalidate
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.while_revalidate),
+            if_error: cc
+                .stale_if_error
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.if_error),
+        }
+    }
+}
+
+fn min_option(current: Option<u64>, new: Option<u64>) -> Option<u64> {
+    match (current, new) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+}
+
+/// freshness lifetime of a response: `s-maxage`, then `max-age`, then
+/// `expires` relative to `date`, falling back to `default_ttl` when the
+/// origin gave no explicit lifetime. `None` when there is neither.
+pub fn freshness_lifetime(
+    cc: &CacheControl,
+    headers: &HeaderMap,
+    default_ttl: Option<Duration>,
+    now: SystemTime,
+) -> Option<Duration> {
+    if let Some(seconds) = cc.s_maxage.or(cc.max_age) {
+        return Some(Duration::from_secs(seconds));
+    }
+    if let Some(expires) = headers.get(header::EXPIRES) {
+        // an invalid expires value (commonly "0") means already expired
+        let Some(expires) = expires.to_str().ok().and_then(|v| httpdate::parse_http_date(v).ok()) else {
+            return Some(Duration::ZERO);
+        };
+        let date = header_date(headers, header::DATE).unwrap_or(now);
+        return Some(expires.duration_since(date).unwrap_or(Duration::ZERO));
+    }
+    default_ttl
+}
+
+/// age the response already had when it reached us, taken from the `age`
+/// header. the apparent age derived from `date` is deliberately ignored so
+/// that clock skew between origin and edge cannot shorten lifetimes.
+pub fn initial_age(headers: &HeaderMap) -> Duration {
+    headers
+        .get(header::AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::ZERO)
+}
+
+/// request header names listed in the response's `vary` header, lowercased
+/// and de-duplicated. returns `None` for `vary: *`, which matches no request.
+pub fn vary_fields(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut fields: Vec<HeaderName> = Vec::new();
+    for value in headers.get_all(header::VARY) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for field in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if field == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(field.to_ascii_lowercase().as_bytes()) {
+                if !fields.contains(&name) {
+                    fields.push(name);
+                }
+            }
+        }
+    }
+    fields.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    Some(fields)
+}
+
+/// whether the response carries an `etag` or `last-modified` a conditional
+/// request can be validated against
+pub fn has_validators(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED)
+}
+
+/// whether a response to a GET may be stored by a shared cache, and so also
+/// handed to clients other than the one it was fetched for
+pub fn is_storable(request_cc: &CacheControl, request_authorized: bool, response_headers: &HeaderMap) -> bool {
+    let cc = CacheControl::from_headers(response_headers);
+
+    if request_cc.no_store || cc.no_store || cc.private {
+        return false;
+    }
+    // personalised responses
+    if response_headers.contains_key(header::SET_COOKIE) {
+        return false;
+    }
+    // `vary: *` can never be matched by a later request
+    if vary_fields(response_headers).is_none() {
+        return false;
+    }
+    // authorised requests are only shareable when the origin says so explicitly
+    !request_authorized || cc.public || cc.s_maxage.is_some() || cc.must_revalidate
+}
+
+/// decides whether a response to a GET may be stored by a shared cache and,
+/// if so, how long it remains fresh from now.
+///
+/// returns `None` when the response must not be stored, when it has no
+/// explicit lifetime and no `default_ttl` applies, or when it is already
+/// stale on arrival. `no-cache` responses are stored with a zero ttl, so every
+/// use revalidates them with the origin, unless they carry no validator to
+/// revalidate with.
+pub fn storable_ttl(
+    request_cc: &CacheControl,
+    request_authorized: bool,
+    response_headers: &HeaderMap,
+    default_ttl: Option<Duration>,
+    now: SystemTime,
+) -> Option<Duration> {
+    if !is_storable(request_cc, request_authorized, response_headers) {
+        return None;
+    }
+
+    let cc = CacheControl::from_headers(response_headers);
+    if cc.no_cache {
+        return has_validators(response_headers).then_some(Duration::ZERO);
+    }
+    let lifetime = freshness_lifetime(&cc, response_headers, default_ttl, now)?;
+    let ttl = lifetime.saturating_sub(initial_age(response_headers));
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn ttl_with_default(response: &[(&str, &str)], default_ttl: Option<Duration>) -> Option<u64> {
+        storable_ttl(&CacheControl::default(), false, &headers(response), default_ttl, SystemTime::now())
+            .map(|ttl| ttl.as_secs())
+    }
+
+    fn ttl_for(request_cc: &CacheControl, authorized: bool, response: &[(&str, &str)]) -> Option<u64> {
+        storable_ttl(request_cc, authorized, &headers(response), Some(Duration::from_secs(10)), SystemTime::now())
+            .map(|ttl| ttl.as_secs())
+    }
+
+    fn ttl(response: &[(&str, &str)]) -> Option<u64> {
+        ttl_for(&CacheControl::default(), false, response)
+    }
+
+    #[test]
+    fn cache_control_directives_are_merged() {
+        let cc = CacheControl::from_headers(&headers(&[
+            ("cache-control", "public, Max-Age=\"60\", s-maxage=abc"),
+            ("cache-control", "max-age=30, stale-while-revalidate=5, unknown"),
+        ]));
+        assert!(cc.public);
+        assert!(!cc.private);
+        assert_eq!(cc.max_age, Some(30));
+        assert_eq!(cc.s_maxage, None);
+        assert_eq!(cc.stale_while_revalidate, Some(5));
+    }
+
+    #[test]
+    fn lifetime_prefers_s_maxage_then_max_age_then_expires() {
+        assert_eq!(ttl(&[("cache-control", "max-age=60, s-maxage=120")]), Some(120));
+        assert_eq!(ttl(&[("cache-control", "max-age=60"), ("expires", "Thu, 01 Jan 2099 00:00:00 GMT")]), Some(60));
+        assert_eq!(
+            ttl(&[("date", "Wed, 21 Oct 2015 07:28:00 GMT"), ("expires", "Wed, 21 Oct 2015 07:29:40 GMT")]),
+            Some(100)
+        );
+        assert_eq!(ttl(&[]), Some(10));
+    }
+
+    #[test]
+    fn explicit_freshness_overrides_a_disabled_default() {
+        assert_eq!(ttl_with_default(&[("cache-control", "max-age=60")], None), Some(60));
+        assert_eq!(ttl_with_default(&[("cache-control", "public")], None), None);
+    }
+
+    #[test]
+    fn expired_or_aged_out_responses_are_not_stored() {
+        assert_eq!(ttl(&[("expires", "0")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=0")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=60"), ("age", "45")]), Some(15));
+        assert_eq!(ttl(&[("cache-control", "max-age=60"), ("age", "90")]), None);
+    }
+
+    #[test]
+    fn private_and_personalised_responses_are_not_stored() {
+        for response in [
+            &[("cache-control", "private, max-age=60")][..],
+            &[("cache-control", "no-store")],
+            &[("set-cookie", "session=1")],
+            &[("vary", "accept-encoding, *")],
+        ] {
+            assert_eq!(ttl(response), None, "{:?}", response);
+        }
+        let no_store = CacheControl {
+            no_store: true,
+            ..CacheControl::default()
+        };
+        assert_eq!(ttl_for(&no_store, false, &[]), None);
+    }
+
+    #[test]
+    fn no_cache_responses_are_stored_for_revalidation() {
+        assert_eq!(ttl(&[("cache-control", "no-cache, max-age=60"), ("etag", "\"v1\"")]), Some(0));
+        assert_eq!(
+            ttl(&[("cache-control", "no-cache"), ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")]),
+            Some(0)
+        );
+        // nothing to revalidate with
+        assert_eq!(ttl(&[("cache-control", "no-cache")]), None);
+        assert_eq!(ttl(&[("cache-control", "no-cache, no-store"), ("etag", "\"v1\"")]), None);
+    }
+
+    #[test]
+    fn authorized_requests_need_explicit_permission() {
+        let cc = CacheControl::default();
+        assert_eq!(ttl_for(&cc, true, &[("cache-control", "max-age=60")]), None);
+        assert_eq!(ttl_for(&cc, true, &[("cache-control", "public, max-age=60")]), Some(60));
+        assert_eq!(ttl_for(&cc, true, &[("cache-control", "s-maxage=60")]), Some(60));
+    }
+
+    #[test]
+    fn vary_fields_are_normalised() {
+        assert_eq!(
+            vary_fields(&headers(&[("vary", "Accept-Encoding, accept-language"), ("vary", "accept-encoding")])),
+            Some(vec![HeaderName::from_static("accept-encoding"), HeaderName::from_static("accept-language")])
+        );
+        assert_eq!(vary_fields(&headers(&[("vary", "*")])), None);
+        assert_eq!(vary_fields(&HeaderMap::new()), Some(vec![]));
+    }
+
+    #[test]
+    fn stale_windows_fall_back_to_defaults_unless_revalidation_is_required() {
+        let defaults = StaleWindows {
+            while_revalidate: Duration::from_secs(30),
+            if_error: Duration::from_secs(300),
+        };
+        let windows = StaleWindows::for_response(&headers(&[("cache-control", "stale-if-error=60")]), &defaults);
+        assert_eq!(windows.while_revalidate, Duration::from_secs(30));
+        assert_eq!(windows.if_error, Duration::from_secs(60));
+        let windows = StaleWindows::for_response(&headers(&[("cache-control", "must-revalidate")]), &defaults);
+        assert_eq!(windows.while_revalidate, Duration::ZERO);
+        assert_eq!(windows.if_error, Duration::ZERO);
+        let windows = StaleWindows::for_response(
+            &headers(&[("cache-control", "no-cache, stale-while-revalidate=60, stale-if-error=60")]),
+            &defaults,
+        );
+        assert_eq!(windows.while_revalidate, Duration::ZERO);
+        assert_eq!(windows.if_error, Duration::from_secs(60));
+    }
+}
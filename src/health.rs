@@ -0,0 +1,319 @@
+//! active health probes of origin servers.
+//!
+//! an origin with a `health_check` gets every server of its pool probed on
+//! an interval. a server is marked down after `unhealthy_threshold` failed
+//! probes in a row and back up after `healthy_threshold` passing ones:
+//!
+//! ```json
+//! "health_check": {
+//!   "path": "/healthz", "interval_ms": 5000, "timeout_ms": 2000,
+//!   "expected_status": [200, 204], "healthy_threshold": 2, "unhealthy_threshold": 3
+//! }
+//! ```
+
+use crate::config::Config;
+use crate::routing::{Origin, Router};
+use crate::util::{Result, ShadowError};
+use http::StatusCode;
+use log::debug;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// how the servers of one origin are probed
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// statuses counting as healthy, any 2xx when empty
+    pub expected_status: Vec<StatusCode>,
+    pub healthy_threshold: u32,
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            expected_status: Vec::new(),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+fn config_error(message: impl Into<String>) -> ShadowError {
+    ShadowError::Config(format!("health_check: {}", message.into()))
+}
+
+impl HealthCheck {
+    /// the health check of the `ORIGIN_URL` origin, `None` unless a path is set
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let path = match &config.origin_health_check_path {
+            Some(path) if path.starts_with('/') => path.clone(),
+            Some(_) => return Err(config_error("ORIGIN_HEALTH_CHECK_PATH must be an absolute path")),
+            None => return Ok(None),
+        };
+        let positive = |name: &str, value: u64| {
+            (value > 0)
+                .then_some(value)
+                .ok_or_else(|| config_error(format!("{} must be positive", name)))
+        };
+        let millis = |name: &str, value: u64| positive(name, value).map(Duration::from_millis);
+        let count = |name: &str, value: u32| positive(name, value.into()).map(|n| n as u32);
+        let expected_status = config
+            .origin_health_check_statuses
+            .iter()
+            .map(|status| StatusCode::from_u16(*status).map_err(|_| config_error(format!("invalid status {}", status))))
+            .collect::<Result<_>>()?;
+        Ok(Some(Self {
+            path,
+            interval: millis(
+                "ORIGIN_HEALTH_CHECK_INTERVAL_MS",
+                config.origin_health_check_interval_ms,
+            )?,
+            timeout: millis("ORIGIN_HEALTH_CHECK_TIMEOUT_MS", config.origin_health_check_timeout_ms)?,
+            expected_status,
+            healthy_threshold: count("ORIGIN_HEALTHY_THRESHOLD", config.origin_healthy_threshold)?,
+            unhealthy_threshold: count("ORIGIN_UNHEALTHY_THRESHOLD", config.origin_unhealthy_threshold)?,
+        }))
+    }
+
+    pub fn from_json(options: &Map<String, Value>) -> Result<Self> {
+        let mut check = Self::default();
+        for (option, value) in options {
+            let number = || {
+                value
+                    .as_u64()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| config_error(format!("`{}` must be a positive number", option)))
+            };
+            match option.as_str() {
+                "path" => {
+                    check.path = value
+                        .as_str()
+                        .filter(|path| path.starts_with('/'))
+                        .ok_or_else(|| config_error("`path` must be an absolute path"))?
+                        .to_string()
+                }
+                "interval_ms" => check.interval = Duration::from_millis(number()?),
+                "timeout_ms" => check.timeout = Duration::from_millis(number()?),
+                "healthy_threshold" => check.healthy_threshold = number()? as u32,
+                "unhealthy_threshold" => check.unhealthy_threshold = number()? as u32,
+                "expected_status" => {
+                    check.expected_status = value
+                        .as_array()
+                        .and_then(|statuses| {
+                            statuses
+                                .iter()
+                                .map(|s| StatusCode::from_u16(u16::try_from(s.as_u64()?).ok()?).ok())
+                                .collect()
+                        })
+                        .ok_or_else(|| config_error("`expected_status` must be a list of status codes"))?
+                }
+                _ => return Err(config_error(format!("unknown option `{}`", option))),
+            }
+        }
+        Ok(check)
+    }
+
+    fn is_expected(&self, status: StatusCode) -> bool {
+        if self.expected_status.is_empty() {
+            status.is_success()
+        } else {
+            self.expected_status.contains(&status)
+        }
+    }
+}
+
+/// starts probing every origin that has a health check configured
+pub fn spawn_probes(router: &Router) {
+    for origin in router.origins() {
+        if let Some(check) = origin.health_check.clone() {
+            actix_web::rt::spawn(probe_loop(origin.clone(), check));
+        }
+    }
+}
+
+async fn probe_loop(origin: Arc<Origin>, check: HealthCheck) {
+    let mut interval = tokio::time::interval(check.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let probes = origin.fetcher.pool().upstreams().iter().map(|upstream| {
+            let origin = &origin;
+            let check = &check;
+            async move {
+                let success = match origin.fetcher.probe(upstream, &check.path, check.timeout).await {
+                    Ok(status) => {
+                        debug!("health probe of {} answered {}", upstream.url, status);
+                        check.is_expected(status)
+                    }
+                    Err(e) => {
+                        debug!("health probe of {} failed: {}", upstream.url, e);
+                        false
+                    }
+                };
+                upstream.record_probe(success, check.healthy_threshold, check.unhealthy_threshold);
+            }
+        });
+        futures_util::future::join_all(probes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::Upstream;
+    use clap::Parser;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn parse(options: Value) -> Result<HealthCheck> {
+        HealthCheck::from_json(options.as_object().unwrap())
+    }
+
+    #[test]
+    fn options_override_the_defaults() {
+        let check = parse(json!({})).unwrap();
+        assert_eq!(check.path, "/");
+        assert_eq!((check.healthy_threshold, check.unhealthy_threshold), (2, 3));
+
+        let check = parse(json!({
+            "path": "/healthz", "interval_ms": 1000, "timeout_ms": 250,
+            "expected_status": [200, 204], "healthy_threshold": 1, "unhealthy_threshold": 5
+        }))
+        .unwrap();
+        assert_eq!(check.path, "/healthz");
+        assert_eq!(check.interval, Duration::from_secs(1));
+        assert_eq!(check.timeout, Duration::from_millis(250));
+        assert_eq!(check.expected_status, [StatusCode::OK, StatusCode::NO_CONTENT]);
+        assert_eq!((check.healthy_threshold, check.unhealthy_threshold), (1, 5));
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        assert!(parse(json!({"path": "healthz"})).is_err());
+        assert!(parse(json!({"healthy_threshold": 0})).is_err());
+        assert!(parse(json!({"unhealthy_threshold": -1})).is_err());
+        assert!(parse(json!({"interval_ms": "5s"})).is_err());
+        assert!(parse(json!({"expected_status": 200})).is_err());
+        assert!(parse(json!({"expected_status": [200, 1000]})).is_err());
+        assert!(parse(json!({"expected_status": ["200"]})).is_err());
+        assert!(parse(json!({"method": "HEAD"})).is_err());
+    }
+
+    #[test]
+    fn expected_status_defaults_to_any_success() {
+        let check = HealthCheck::default();
+        assert!(check.is_expected(StatusCode::OK));
+        assert!(check.is_expected(StatusCode::NO_CONTENT));
+        assert!(!check.is_expected(StatusCode::SERVICE_UNAVAILABLE));
+
+        let check = parse(json!({"expected_status": [503]})).unwrap();
+        assert!(check.is_expected(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!check.is_expected(StatusCode::OK));
+    }
+
+    #[test]
+    fn thresholds_take_servers_down_and_up() {
+        let check = HealthCheck::default();
+        let upstream = Upstream::new("http://10.0.0.1/", 1).unwrap();
+        let probe = |success| upstream.record_probe(success, check.healthy_threshold, check.unhealthy_threshold);
+
+        // a success in between restarts the count of failures
+        probe(false);
+        probe(false);
+        probe(true);
+        probe(false);
+        probe(false);
+        assert!(upstream.probe_healthy());
+        probe(false);
+        assert!(!upstream.probe_healthy());
+
+        probe(true);
+        assert!(!upstream.probe_healthy());
+        probe(true);
+        assert!(upstream.probe_healthy());
+    }
+
+    #[test]
+    fn origin_url_check_is_read_from_the_command_line() {
+        let parse = |args: &[&str]| HealthCheck::from_config(&Config::parse_from(["shadowstep"].iter().chain(args)));
+        assert!(parse(&[]).unwrap().is_none());
+
+        let check = parse(&[
+            "--origin-health-check-path",
+            "/healthz",
+            "--origin-health-check-statuses",
+            "200,204",
+            "--origin-unhealthy-threshold",
+            "1",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(check.path, "/healthz");
+        assert_eq!(check.expected_status, [StatusCode::OK, StatusCode::NO_CONTENT]);
+        assert_eq!((check.healthy_threshold, check.unhealthy_threshold), (2, 1));
+
+        assert!(parse(&["--origin-health-check-path", "healthz"]).is_err());
+        assert!(parse(&["--origin-health-check-path", "/", "--origin-health-check-statuses", "1000"]).is_err());
+        assert!(parse(&["--origin-health-check-path", "/", "--origin-healthy-threshold", "0"]).is_err());
+    }
+
+    async fn until(condition: impl Fn() -> bool) {
+        for _ in 0..400 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn probes_take_the_origin_url_server_down_and_up() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let status = Arc::new(AtomicU16::new(200));
+        let answered = status.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let status = answered.clone();
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request).await;
+                    assert!(request.starts_with(b"GET /healthz "));
+                    let response = format!(
+                        "HTTP/1.1 {} probe\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        status.load(Ordering::Relaxed)
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        let config = Config::parse_from([
+            "shadowstep".to_string(),
+            format!("--origin-url=http://{}", address),
+            "--origin-health-check-path=/healthz".to_string(),
+            "--origin-health-check-interval-ms=10".to_string(),
+            "--origin-healthy-threshold=2".to_string(),
+            "--origin-unhealthy-threshold=2".to_string(),
+        ]);
+        let router = Router::new(&config).unwrap();
+        let origin = router.route(&http::Method::GET, "example.com", "/").clone();
+        let probes = tokio::spawn(probe_loop(origin.clone(), origin.health_check.clone().unwrap()));
+        let upstream = &origin.fetcher.pool().upstreams()[0];
+
+        status.store(503, Ordering::Relaxed);
+        until(|| !upstream.probe_healthy()).await;
+        status.store(200, Ordering::Relaxed);
+        until(|| upstream.probe_healthy()).await;
+        probes.abort();
+    }
+}
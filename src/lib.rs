@@ -0,0 +1,37 @@
+//! shadowstep - a minimal edge CDN implementation
+//!
+//! provides caching reverse proxy functionality with:
+//! - HTTP/1.1 and HTTP/2 support, towards clients and origins
+//! - in-memory caching with ttl expiry
+//! - TLS termination
+//!
+//! the binary is a thin wrapper around [`server::run`], so the same pipeline
+//! can be embedded in other binaries or driven from integration tests.
+//!
+//! author: jamiehdev
+
+pub mod admin;
+pub mod body;
+pub mod breaker;
+pub mod cache;
+pub mod cache_key;
+pub mod coalesce;
+pub mod config;
+pub mod disk;
+pub mod fetcher;
+pub mod forwarding;
+pub mod freshness;
+pub mod health;
+pub mod listener;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod range;
+pub mod retry;
+pub mod routing;
+pub mod server;
+pub mod tls;
+pub mod tunnel;
+pub mod util;
+
+pub use config::Config;
+pub use util::{Result, ShadowError};
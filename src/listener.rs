@@ -0,0 +1,338 @@
+//! the http and https listeners.
+//!
+//! they are assembled from actix-server and actix-http instead of with
+//! `HttpServer`, which has no place to read a PROXY protocol header (see
+//! [`crate::proxy_protocol`]) before http or the tls handshake starts. a
+//! listener with the protocol enabled takes the peer address from the header.
+//!
+//! https offers http/2 through alpn. plain http listeners can take http/2
+//! with prior knowledge (h2c) as well, recognised by its connection preface.
+//!
+//! whether a connection is secure and the address it came in on are handed
+//! to handlers as [`Connection`], and to actix as the app's `AppConfig` so
+//! `connection_info()` and url generation see the listener's scheme and
+//! address as they would behind `HttpServer`.
+//!
+//! requests sending `Expect: 100-continue` with a body declared larger than
+//! the limit are refused before the `100 Continue`, so the client never
+//! starts sending it.
+
+use crate::proxy_protocol;
+use actix_http::body::{BoxBody, MessageBody};
+use actix_http::error::DispatchError;
+use actix_http::header::CONTENT_LENGTH;
+use actix_http::{HttpService, KeepAlive, Protocol, Request, Response, StatusCode};
+use actix_server::ServerBuilder;
+use actix_service::{fn_service, map_config, IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt as _};
+use actix_web::dev::AppConfig;
+use actix_web::{Error, HttpRequest};
+use log::debug;
+use rustls::ServerConfig;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// how long a tls handshake may take, as with `HttpServer`
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/// how long a plain connection may take to send its first bytes when h2c is
+/// enabled, as actix' default for the request head
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(5);
+/// start of the http/2 connection preface
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2";
+
+/// one address to accept connections on
+pub struct Listener {
+    pub addr: String,
+    /// terminate tls with this config, whose alpn protocols should include
+    /// `h2` for http/2; plain http when `None`
+    pub tls: Option<ServerConfig>,
+    /// connections start with a PROXY protocol header
+    pub proxy_protocol: bool,
+    /// plain connections may speak http/2 with prior knowledge
+    pub h2c: bool,
+    /// largest request body accepted, in bytes
+    pub max_request_body: Option<u64>,
+}
+
+/// what the listener knows about a connection, available to handlers
+/// through `HttpRequest::conn_data`
+#[derive(Clone, Copy, Debug)]
+pub struct Connection {
+    /// tls was terminated on the connection
+    pub secure: bool,
+    /// address the connection was accepted on
+    pub local_addr: SocketAddr,
+}
+
+impl Connection {
+    /// the connection a request arrived on, `None` for requests that did not
+    /// come through a listener
+    pub fn of(req: &HttpRequest) -> Option<Connection> {
+        req.conn_data::<Connection>().copied()
+    }
+
+    /// whether the request arrived over tls
+    pub fn is_secure(req: &HttpRequest) -> bool {
+        Self::of(req).is_some_and(|connection| connection.secure)
+    }
+}
+
+/// an accepted connection, after the tls handshake if there was one
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            Stream::Tls(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            Stream::Tls(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            Stream::Tls(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Plain(io) => io.is_write_vectored(),
+            Stream::Tls(io) => io.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_flush(cx),
+            Stream::Tls(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            Stream::Tls(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+}
+
+/// reads the PROXY header if the listener expects one and returns the
+/// client's address: the one from the header, or the peer's
+async fn peer_address(io: &mut TcpStream, proxy_protocol: bool) -> io::Result<Option<SocketAddr>> {
+    let peer = io.peer_addr().ok();
+    if !proxy_protocol {
+        return Ok(peer);
+    }
+    let header = tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, proxy_protocol::read_header(io))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no proxy protocol header in time"))?;
+    match header {
+        Ok(Some(addresses)) => Ok(Some(addresses.source)),
+        // the proxy's own connection, e.g. a health check
+        Ok(None) => Ok(peer),
+        Err(e) => {
+            debug!("dropping connection from {:?}: {}", peer, e);
+            Err(e)
+        }
+    }
+}
+
+/// whether a plain connection starts with the http/2 preface. like actix'
+/// `tcp_auto_h2c` this peeks once, so a preface split over several packets
+/// is taken for http/1.1.
+async fn starts_with_h2_preface(io: &TcpStream) -> io::Result<bool> {
+    let mut start = [0u8; H2_PREFACE.len()];
+    let read = tokio::time::timeout(PREFACE_TIMEOUT, io.peek(&mut start))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request in time"))??;
+    Ok(&start[..read] == H2_PREFACE)
+}
+
+/// accepts a connection: the PROXY header, then the tls handshake with its
+/// alpn choice of http/2, or on plain connections the h2c preface
+async fn accept(
+    mut io: TcpStream,
+    proxy_protocol: bool,
+    h2c: bool,
+    tls: Option<TlsAcceptor>,
+) -> io::Result<(Stream, Protocol, Option<SocketAddr>)> {
+    let peer = peer_address(&mut io, proxy_protocol).await?;
+    let Some(acceptor) = tls else {
+        let protocol = if h2c && starts_with_h2_preface(&io).await? {
+            Protocol::Http2
+        } else {
+            Protocol::Http1
+        };
+        return Ok((Stream::Plain(io), protocol, peer));
+    };
+    let io = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(io))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out"))??;
+    let protocol = match io.get_ref().1.alpn_protocol() {
+        Some(b"h2") => Protocol::Http2,
+        _ => Protocol::Http1,
+    };
+    Ok((Stream::Tls(Box::new(io)), protocol, peer))
+}
+
+/// answers `Expect: 100-continue`, going ahead unless the declared body is
+/// over `max_body`. the `413` closes the connection, as the body is not read.
+async fn expect_continue(req: Request, max_body: Option<u64>) -> Result<Request, Response<BoxBody>> {
+    let declared = req
+        .head()
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match (declared, max_body) {
+        (Some(declared), Some(max_body)) if declared > max_body => Err(Response::build(StatusCode::PAYLOAD_TOO_LARGE)
+            .force_close()
+            .body(format!("request body exceeds {} bytes", max_body))
+            .map_into_boxed_body()),
+        _ => Ok(req),
+    }
+}
+
+/// adds a listener serving the app built by `app` to the server
+pub fn bind<F, I, S, B>(
+    builder: ServerBuilder,
+    listener: &Listener,
+    keep_alive: Duration,
+    app: F,
+) -> io::Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let addr = listener
+        .addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot resolve {}", listener.addr)))?;
+    let secure = listener.tls.is_some();
+    let proxy_protocol = listener.proxy_protocol;
+    let h2c = listener.h2c;
+    let max_request_body = listener.max_request_body;
+    let tls = listener.tls.clone().map(|config| TlsAcceptor::from(Arc::new(config)));
+
+    builder.bind(format!("shadowstep-{}", addr), addr, move || {
+        let app = app.clone();
+        let http = HttpService::build()
+            .keep_alive(KeepAlive::Timeout(keep_alive))
+            .local_addr(addr)
+            .expect(fn_service(move |req| expect_continue(req, max_request_body)))
+            .on_connect_ext(move |_: &Stream, extensions| {
+                extensions.insert(Connection {
+                    secure,
+                    local_addr: addr,
+                });
+            })
+            .finish(map_config(
+                app().into_factory().map_err(|err| err.into().error_response()),
+                // what `HttpServer` passes; its constructor is only public
+                // under this name
+                move |_| AppConfig::__priv_test_new(secure, addr.to_string(), addr),
+            ));
+        let tls = tls.clone();
+        fn_service(move |io: TcpStream| {
+            let tls = tls.clone();
+            async move { accept(io, proxy_protocol, h2c, tls).await.map_err(DispatchError::Io) }
+        })
+        .and_then(http)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse};
+
+    /// an address nothing listens on yet
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn h2c_prior_knowledge_reaches_the_app() {
+        let addr = free_addr();
+        let listener = Listener {
+            addr: addr.to_string(),
+            tls: None,
+            proxy_protocol: false,
+            h2c: true,
+            max_request_body: None,
+        };
+        let app = || {
+            App::new().route(
+                "/",
+                web::get().to(|req: HttpRequest| async move {
+                    let config = req.app_config();
+                    let connection = Connection::of(&req).unwrap();
+                    HttpResponse::Ok().body(format!(
+                        "{:?} {} {} {} {}",
+                        req.version(),
+                        config.secure(),
+                        config.local_addr(),
+                        connection.secure,
+                        connection.local_addr
+                    ))
+                }),
+            )
+        };
+        let server = bind(actix_server::Server::build(), &listener, Duration::from_secs(5), app)
+            .unwrap()
+            .workers(1)
+            .disable_signals()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let uri: http::Uri = format!("http://{}/", addr).parse().unwrap();
+        let get = |client: hyper::Client<hyper::client::HttpConnector>| {
+            let uri = uri.clone();
+            async move {
+                let response = client.get(uri).await.unwrap();
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+        let h2c = get(hyper::Client::builder().http2_only(true).build_http()).await;
+        assert_eq!(h2c, format!("HTTP/2.0 false {} false {}", addr, addr));
+        // http/1.1 is still served next to it
+        let http1 = get(hyper::Client::new()).await;
+        assert_eq!(http1, format!("HTTP/1.1 false {} false {}", addr, addr));
+
+        handle.stop(false).await;
+    }
+}
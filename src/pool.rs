@@ -0,0 +1,446 @@
+//! the servers behind one origin and how requests are spread over them.
+//!
+//! servers can be taken out of rotation in two ways: active health probes
+//! (see [`crate::health`]) mark them down after repeated failed probes, and
+//! passive outlier detection ejects them for a while after consecutive
+//! failed requests. when every server of a pool is out, requests are spread
+//! over all of them anyway, a failing origin is still better than none.
+
+use crate::util::{Result, ShadowError};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// points each server gets on the consistent hash ring per unit of weight
+const RING_POINTS_PER_WEIGHT: u32 = 100;
+
+/// highest server weight, keeping the hash ring at most 100k points per server
+const MAX_WEIGHT: u32 = 1000;
+
+/// how a server is picked for each request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balance {
+    /// servers in turn, ignoring weights
+    RoundRobin,
+    /// the server with the fewest requests in flight, ties broken by weight
+    LeastConnections,
+    /// servers in turn, each as often as its weight (smooth weighted round robin)
+    Weighted,
+    /// the same server for the same cache key, so each object is cached by
+    /// one backend; only keys of a removed server move
+    ConsistentHash,
+}
+
+impl Balance {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_connections" => Ok(Self::LeastConnections),
+            "weighted" => Ok(Self::Weighted),
+            "consistent_hash" => Ok(Self::ConsistentHash),
+            other => Err(ShadowError::Config(format!(
+                "unknown balance strategy `{}`, expected round_robin, least_connections, weighted or consistent_hash",
+                other
+            ))),
+        }
+    }
+}
+
+/// passive outlier detection: servers failing `consecutive_errors` requests
+/// in a row (connection errors, timeouts, 502/503/504) are ejected from the
+/// pool for `ejection`. 0 errors disables it.
+#[derive(Clone, Copy, Debug)]
+pub struct OutlierDetection {
+    pub consecutive_errors: u32,
+    pub ejection: Duration,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_errors: 5,
+            ejection: Duration::from_secs(30),
+        }
+    }
+}
+
+/// one server of the pool
+pub struct Upstream {
+    pub url: url::Url,
+    pub weight: u32,
+    /// requests sent to this server whose response has not been fully read
+    in_flight: AtomicUsize,
+    /// false once active health probes have failed too often
+    probe_healthy: AtomicBool,
+    probe_successes: AtomicU32,
+    probe_failures: AtomicU32,
+    /// failed requests in a row, for outlier detection
+    consecutive_errors: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    pub fn new(url: &str, weight: u32) -> Result<Self> {
+        if !(1..=MAX_WEIGHT).contains(&weight) {
+            return Err(ShadowError::Config(format!("weight of {} must be between 1 and {}", url, MAX_WEIGHT)));
+        }
+        Ok(Self {
+            url: url::Url::parse(url)?,
+            weight,
+            in_flight: AtomicUsize::new(0),
+            probe_healthy: AtomicBool::new(true),
+            probe_successes: AtomicU32::new(0),
+            probe_failures: AtomicU32::new(0),
+            consecutive_errors: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors.load(Ordering::Relaxed)
+    }
+
+    /// passing active health probes
+    pub fn probe_healthy(&self) -> bool {
+        self.probe_healthy.load(Ordering::Relaxed)
+    }
+
+    /// ejected by outlier detection; the ejection is lifted once it expired
+    pub fn is_ejected(&self) -> bool {
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                *ejected_until = None;
+                info!("origin server {} returns to rotation after ejection", self.url);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// takes requests: healthy and not ejected
+    pub fn is_available(&self) -> bool {
+        self.probe_healthy() && !self.is_ejected()
+    }
+
+    /// records an active probe, marking the server down after `unhealthy`
+    /// failures in a row and up again after `healthy` successes in a row
+    pub fn record_probe(&self, success: bool, healthy: u32, unhealthy: u32) {
+        if success {
+            self.probe_failures.store(0, Ordering::Relaxed);
+            let successes = self.probe_successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if successes >= healthy && !self.probe_healthy.swap(true, Ordering::Relaxed) {
+                info!("origin server {} is healthy again", self.url);
+                // a server that passes its probes gets a fresh start
+                *self.ejected_until.lock().unwrap() = None;
+                self.consecutive_errors.store(0, Ordering::Relaxed);
+            }
+        } else {
+            self.probe_successes.store(0, Ordering::Relaxed);
+            let failures = self.probe_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= unhealthy && self.probe_healthy.swap(false, Ordering::Relaxed) {
+                warn!("origin server {} failed {} health probes, marking it down", self.url, failures);
+            }
+        }
+    }
+
+    /// records the outcome of a proxied request for outlier detection
+    pub fn record_request(&self, success: bool, outliers: &OutlierDetection) {
+        if success {
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+            return;
+        }
+        let errors = self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if outliers.consecutive_errors > 0 && errors >= outliers.consecutive_errors {
+            let mut ejected_until = self.ejected_until.lock().unwrap();
+            if ejected_until.is_none() {
+                warn!(
+                    "origin server {} failed {} requests in a row, ejecting it for {:?}",
+                    self.url, errors, outliers.ejection
+                );
+            }
+            *ejected_until = Some(Instant::now() + outliers.ejection);
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// counts a request as in flight on its server until dropped
+pub struct InFlight(Arc<Upstream>);
+
+impl InFlight {
+    pub fn upstream(&self) -> &Upstream {
+        &self.0
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// the servers of an origin along with the state of the balance strategy
+pub struct OriginPool {
+    upstreams: Vec<Arc<Upstream>>,
+    balance: Balance,
+    outliers: OutlierDetection,
+    next: AtomicUsize,
+    /// current weights of smooth weighted round robin
+    current_weights: Mutex<Vec<i64>>,
+    /// sorted hash ring points and the index of the server owning each
+    ring: Vec<(u64, usize)>,
+}
+
+fn ring_hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    u64::from_be_bytes(digest[..8].try_into().expect("sha256 digest is 32 bytes"))
+}
+
+impl OriginPool {
+    pub fn new(upstreams: Vec<Upstream>, balance: Balance, outliers: OutlierDetection) -> Result<Self> {
+        if upstreams.is_empty() {
+            return Err(ShadowError::Config("an origin needs at least one server".into()));
+        }
+        let mut ring = Vec::new();
+        if balance == Balance::ConsistentHash {
+            for (index, upstream) in upstreams.iter().enumerate() {
+                for point in 0..upstream.weight * RING_POINTS_PER_WEIGHT {
+                    ring.push((ring_hash(format!("{}#{}", upstream.url, point).as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Ok(Self {
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+            upstreams: upstreams.into_iter().map(Arc::new).collect(),
+            balance,
+            outliers,
+            next: AtomicUsize::new(0),
+            ring,
+        })
+    }
+
+    /// a pool of one server
+    pub fn single(url: &str) -> Result<Self> {
+        Self::new(vec![Upstream::new(url, 1)?], Balance::RoundRobin, OutlierDetection::default())
+    }
+
+    pub fn outliers(&self) -> &OutlierDetection {
+        &self.outliers
+    }
+
+    /// true while at least one server takes requests
+    pub fn is_available(&self) -> bool {
+        self.upstreams.iter().any(|u| u.is_available())
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    /// picks the server for a request; `key` is the cache key, used by
+    /// consistent hashing
+    pub fn select(&self, key: &str) -> InFlight {
+        let available: Vec<bool> = self.upstreams.iter().map(|u| u.is_available()).collect();
+        // with every server out, fall back to all of them
+        let usable = |index: usize| available[index] || !available.contains(&true);
+        let index = match self.upstreams.len() {
+            1 => 0,
+            count => match self.balance {
+                Balance::RoundRobin => {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed);
+                    (0..count).map(|offset| (start + offset) % count).find(|&i| usable(i)).unwrap_or(0)
+                }
+                Balance::LeastConnections => self.least_connections(&usable),
+                Balance::Weighted => self.smooth_weighted(&usable),
+                Balance::ConsistentHash => self.ring_owner(key, &usable),
+            },
+        };
+        let upstream = self.upstreams[index].clone();
+        upstream.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(upstream)
+    }
+
+    fn least_connections(&self, usable: &dyn Fn(usize) -> bool) -> usize {
+        // compare in_flight / weight without dividing; the rotating start
+        // spreads ties instead of always picking the first server
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .filter(|&i| usable(i))
+            .min_by(|&a, &b| {
+                let (a, b) = (&self.upstreams[a], &self.upstreams[b]);
+                (a.in_flight() as u64 * b.weight as u64).cmp(&(b.in_flight() as u64 * a.weight as u64))
+            })
+            .unwrap_or(0)
+    }
+
+    fn smooth_weighted(&self, usable: &dyn Fn(usize) -> bool) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best = None;
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            if !usable(index) {
+                continue;
+            }
+            total += upstream.weight as i64;
+            current[index] += upstream.weight as i64;
+            if best.is_none_or(|best| current[index] > current[best]) {
+                best = Some(index);
+            }
+        }
+        let best = best.unwrap_or(0);
+        current[best] -= total;
+        best
+    }
+
+    /// first usable server clockwise from the key's point on the ring
+    fn ring_owner(&self, key: &str, usable: &dyn Fn(usize) -> bool) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[start..]
+            .iter()
+            .chain(&self.ring[..start])
+            .map(|(_, index)| *index)
+            .find(|&index| usable(index))
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(weights: &[u32], balance: Balance) -> OriginPool {
+        let upstreams = weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| Upstream::new(&format!("http://10.0.0.{}:8080/", i + 1), weight).unwrap())
+            .collect();
+        OriginPool::new(upstreams, balance, OutlierDetection::default()).unwrap()
+    }
+
+    fn host(in_flight: &InFlight) -> String {
+        in_flight.upstream().url.host_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn round_robin_takes_servers_in_turn() {
+        let pool = pool(&[1, 5, 1], Balance::RoundRobin);
+        let picked: Vec<String> = (0..6).map(|_| host(&pool.select(""))).collect();
+        assert_eq!(picked, ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn weighted_is_smooth_and_proportional() {
+        let pool = pool(&[5, 1, 1], Balance::Weighted);
+        let picked: Vec<String> = (0..7).map(|_| host(&pool.select(""))).collect();
+        assert_eq!(picked.iter().filter(|h| *h == "10.0.0.1").count(), 5);
+        // the heavy server is interleaved with the others, not picked five times in a row
+        assert_eq!(picked[..4].iter().filter(|h| *h == "10.0.0.1").count(), 3);
+    }
+
+    #[test]
+    fn least_connections_counts_requests_until_dropped() {
+        let pool = pool(&[1, 1], Balance::LeastConnections);
+        let first = pool.select("");
+        let second = pool.select("");
+        assert_ne!(host(&first), host(&second));
+        let busy = host(&first);
+        drop(second);
+        // the server whose request is still in flight is avoided
+        for _ in 0..4 {
+            assert_ne!(host(&pool.select("")), busy);
+        }
+        assert_eq!(pool.upstreams().iter().map(|u| u.in_flight()).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn consistent_hash_keeps_keys_on_their_server() {
+        let pool = pool(&[1, 1, 1], Balance::ConsistentHash);
+        let keys: Vec<String> = (0..50).map(|i| format!("/object/{}", i)).collect();
+        let owners: Vec<String> = keys.iter().map(|key| host(&pool.select(key))).collect();
+        assert_eq!(keys.iter().map(|key| host(&pool.select(key))).collect::<Vec<_>>(), owners);
+        assert!(owners.iter().any(|h| h == "10.0.0.1") && owners.iter().any(|h| h == "10.0.0.3"));
+
+        // ejecting a server moves only the keys it owned
+        let ejected = &pool.upstreams()[1];
+        for _ in 0..OutlierDetection::default().consecutive_errors {
+            ejected.record_request(false, pool.outliers());
+        }
+        for (key, owner) in keys.iter().zip(&owners) {
+            let now = host(&pool.select(key));
+            assert_ne!(now, "10.0.0.2");
+            if owner != "10.0.0.2" {
+                assert_eq!(&now, owner);
+            }
+        }
+    }
+
+    #[test]
+    fn unavailable_servers_are_skipped_until_none_is_left() {
+        let pool = pool(&[1, 1], Balance::RoundRobin);
+        pool.upstreams()[0].record_probe(false, 1, 1);
+        assert!(pool.is_available());
+        for _ in 0..3 {
+            assert_eq!(host(&pool.select("")), "10.0.0.2");
+        }
+        pool.upstreams()[1].record_probe(false, 1, 1);
+        assert!(!pool.is_available());
+        // with every server down, requests go to all of them anyway
+        let picked: Vec<String> = (0..2).map(|_| host(&pool.select(""))).collect();
+        assert!(picked.contains(&"10.0.0.1".to_string()) && picked.contains(&"10.0.0.2".to_string()));
+
+        pool.upstreams()[0].record_probe(true, 1, 1);
+        assert!(pool.upstreams()[0].is_available());
+    }
+
+    #[test]
+    fn consecutive_errors_eject_and_successes_reset() {
+        let outliers = OutlierDetection {
+            consecutive_errors: 2,
+            ejection: Duration::from_secs(60),
+        };
+        let upstream = Upstream::new("http://10.0.0.1/", 1).unwrap();
+        upstream.record_request(false, &outliers);
+        upstream.record_request(true, &outliers);
+        upstream.record_request(false, &outliers);
+        assert!(upstream.is_available());
+        upstream.record_request(false, &outliers);
+        assert!(upstream.is_ejected());
+
+        let disabled = OutlierDetection {
+            consecutive_errors: 0,
+            ..outliers
+        };
+        let upstream = Upstream::new("http://10.0.0.1/", 1).unwrap();
+        for _ in 0..10 {
+            upstream.record_request(false, &disabled);
+        }
+        assert!(!upstream.is_ejected());
+    }
+
+    #[test]
+    fn invalid_pools_are_refused() {
+        assert!(Upstream::new("http://10.0.0.1/", 0).is_err());
+        assert!(Upstream::new("http://10.0.0.1/", MAX_WEIGHT).is_ok());
+        assert!(Upstream::new("http://10.0.0.1/", MAX_WEIGHT + 1).is_err());
+        assert!(Upstream::new("http://10.0.0.1/", u32::MAX).is_err());
+        assert!(OriginPool::new(Vec::new(), Balance::RoundRobin, OutlierDetection::default()).is_err());
+        assert!(Balance::parse("random").is_err());
+        assert_eq!(Balance::parse("consistent_hash").unwrap(), Balance::ConsistentHash);
+    }
+}
@@ -0,0 +1,385 @@
+//! haproxy PROXY protocol, version 1 (text) and 2 (binary).
+//!
+//! a load balancer passing tcp connections through puts a PROXY header in
+//! front of each one, naming the client it accepted the connection from.
+//! listeners with the protocol enabled require the header and use the
+//! client address it carries as the peer address. origins with
+//! `proxy_protocol` set get a header on every connection, which are then
+//! no longer pooled since each describes a single client.
+
+use crate::util::{Result, ShadowError};
+use http::Uri;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// how long a new connection may take to send its PROXY header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// longest version 1 header including the line break
+const V1_MAX_LENGTH: usize = 107;
+
+/// version of the PROXY headers sent to an origin
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProxyVersion {
+    V1,
+    V2,
+}
+
+impl ProxyVersion {
+    pub fn parse(name: &str) -> Result<Option<Self>> {
+        match name {
+            "v1" => Ok(Some(Self::V1)),
+            "v2" => Ok(Some(Self::V2)),
+            "none" => Ok(None),
+            other => Err(ShadowError::Config(format!(
+                "unknown proxy protocol `{}`, expected v1, v2 or none",
+                other
+            ))),
+        }
+    }
+}
+
+/// the two ends of a proxied connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyAddresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid proxy protocol header: {}", message))
+}
+
+/// reads the PROXY header a connection starts with, leaving everything after
+/// it unread. `None` when the header carries no addresses: connections the
+/// proxy makes itself, such as health checks, and unknown protocols.
+pub async fn read_header(io: &mut TcpStream) -> io::Result<Option<ProxyAddresses>> {
+    // the shortest header, "PROXY UNKNOWN\r\n", is longer than this
+    let mut start = [0u8; 12];
+    io.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut head = [0u8; 4];
+        io.read_exact(&mut head).await?;
+        let mut payload = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+        io.read_exact(&mut payload).await?;
+        return parse_v2(head[0], head[1], &payload);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing signature"));
+    }
+
+    // the rest of the line is taken from what has arrived, so that nothing
+    // past its end is consumed
+    let mut line = start.to_vec();
+    let mut peeked = [0u8; V1_MAX_LENGTH];
+    while !line.ends_with(b"\r\n") {
+        let available = io.peek(&mut peeked[..V1_MAX_LENGTH - line.len()]).await?;
+        if available == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let take = peeked[..available]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(available, |end| end + 1);
+        let mut chunk = vec![0u8; take];
+        io.read_exact(&mut chunk).await?;
+        line.extend_from_slice(&chunk);
+        if line.len() >= V1_MAX_LENGTH && !line.ends_with(b"\r\n") {
+            return Err(invalid("line too long"));
+        }
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxyAddresses>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("not ascii"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("bad address"))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(invalid("address does not match the family"));
+                }
+                Ok(SocketAddr::new(ip, port.parse().map_err(|_| invalid("bad port"))?))
+            };
+            Ok(Some(ProxyAddresses {
+                source: address(source, source_port)?,
+                destination: address(destination, destination_port)?,
+            }))
+        }
+        _ => Err(invalid("malformed line")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<Option<ProxyAddresses>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, addresses are to be ignored
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    // any tlvs after the addresses are skipped
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let ip = |bytes: &[u8]| IpAddr::from(<[u8; 4]>::try_from(bytes).expect("4 bytes"));
+            Ok(Some(ProxyAddresses {
+                source: SocketAddr::new(ip(&payload[0..4]), port(&payload[8..10])),
+                destination: SocketAddr::new(ip(&payload[4..8]), port(&payload[10..12])),
+            }))
+        }
+        0x2 if payload.len() >= 36 => {
+            let ip = |bytes: &[u8]| IpAddr::from(<[u8; 16]>::try_from(bytes).expect("16 bytes"));
+            Ok(Some(ProxyAddresses {
+                source: SocketAddr::new(ip(&payload[0..16]), port(&payload[32..34])),
+                destination: SocketAddr::new(ip(&payload[16..32]), port(&payload[34..36])),
+            }))
+        }
+        0x1 | 0x2 => Err(invalid("address block too short")),
+        // unspecified or unix sockets carry nothing usable
+        _ => Ok(None),
+    }
+}
+
+/// a header describing `addresses`, or a connection of the proxy itself
+/// (`UNKNOWN` / `LOCAL`) when there are none
+pub fn encode(version: ProxyVersion, addresses: Option<ProxyAddresses>) -> Vec<u8> {
+    // both ends have to be of one family; mixed ones are told as ipv6
+    let addresses = addresses.map(|a| match (a.source.ip(), a.destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => (a.source, a.destination),
+        (source, destination) => (
+            SocketAddr::new(IpAddr::V6(to_v6(source)), a.source.port()),
+            SocketAddr::new(IpAddr::V6(to_v6(destination)), a.destination.port()),
+        ),
+    });
+    match version {
+        ProxyVersion::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            match addresses {
+                Some((source, destination)) => {
+                    let mut block = Vec::with_capacity(36);
+                    let family = match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(s), IpAddr::V4(d)) => {
+                            block.extend_from_slice(&s.octets());
+                            block.extend_from_slice(&d.octets());
+                            0x11
+                        }
+                        (s, d) => {
+                            block.extend_from_slice(&to_v6(s).octets());
+                            block.extend_from_slice(&to_v6(d).octets());
+                            0x21
+                        }
+                    };
+                    block.extend_from_slice(&source.port().to_be_bytes());
+                    block.extend_from_slice(&destination.port().to_be_bytes());
+                    header.extend_from_slice(&[0x21, family]);
+                    header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&block);
+                }
+                None => header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]),
+            }
+            header
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+tokio::task_local! {
+    /// the connection an origin request is made for, read by the connector
+    /// when it opens a connection to an origin that expects PROXY headers
+    static REQUEST_ADDRESSES: Option<ProxyAddresses>;
+}
+
+/// runs an origin request on behalf of the client connection `addresses`
+pub async fn with_addresses<F: Future>(addresses: Option<ProxyAddresses>, request: F) -> F::Output {
+    REQUEST_ADDRESSES.scope(addresses, request).await
+}
+
+/// the client connection of the origin request being made, if any
+pub fn request_addresses() -> Option<ProxyAddresses> {
+    REQUEST_ADDRESSES.try_with(|addresses| *addresses).ok().flatten()
+}
+
+/// tcp connector for origins, writing a PROXY header on each new connection
+/// when the origin expects one
+#[derive(Clone)]
+pub struct ProxyConnector {
+    inner: HttpConnector,
+    version: Option<ProxyVersion>,
+}
+
+impl ProxyConnector {
+    pub fn new(inner: HttpConnector, version: Option<ProxyVersion>) -> Self {
+        Self { inner, version }
+    }
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<TcpStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        // read now, while the request's task is polling us
+        let header = self.version.map(|version| encode(version, request_addresses()));
+        Box::pin(async move {
+            let mut stream = connecting.await?;
+            if let Some(header) = header {
+                stream.write_all(&header).await?;
+            }
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// reads a header from a connection that sent `data` and then closed,
+    /// along with whatever the header left unread
+    async fn read(data: &[u8]) -> (io::Result<Option<ProxyAddresses>>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(data).await.unwrap();
+        client.shutdown().await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let header = read_header(&mut server).await;
+        let mut rest = Vec::new();
+        if header.is_ok() {
+            server.read_to_end(&mut rest).await.unwrap();
+        }
+        (header, rest)
+    }
+
+    fn addresses(source: &str, destination: &str) -> ProxyAddresses {
+        ProxyAddresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn encoded_headers_read_back() {
+        let cases = [
+            addresses("192.0.2.1:56324", "198.51.100.2:443"),
+            addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+        ];
+        for version in [ProxyVersion::V1, ProxyVersion::V2] {
+            for expected in cases {
+                let mut data = encode(version, Some(expected));
+                data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                let (header, rest) = read(&data).await;
+                assert_eq!(header.unwrap(), Some(expected), "{:?}", version);
+                assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_families_are_encoded_as_ipv6() {
+        let mixed = addresses("192.0.2.1:1000", "[2001:db8::2]:443");
+        let line = encode(ProxyVersion::V1, Some(mixed));
+        assert_eq!(line, b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 1000 443\r\n");
+        let header = encode(ProxyVersion::V2, Some(mixed));
+        assert_eq!(header[13], 0x21);
+        assert_eq!(
+            parse_v2(header[12], header[13], &header[16..]).unwrap(),
+            Some(addresses("[::ffff:192.0.2.1]:1000", "[2001:db8::2]:443"))
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_connections_carry_no_addresses() {
+        for version in [ProxyVersion::V1, ProxyVersion::V2] {
+            let (header, _) = read(&encode(version, None)).await;
+            assert_eq!(header.unwrap(), None, "{:?}", version);
+        }
+        assert_eq!(parse_v1(b"PROXY UNKNOWN 192.0.2.1 198.51.100.2 1 2").unwrap(), None);
+        // LOCAL with an address block still tells nothing
+        let local = encode(ProxyVersion::V2, Some(addresses("192.0.2.1:1", "198.51.100.2:2")));
+        assert_eq!(parse_v2(0x20, local[13], &local[16..]).unwrap(), None);
+        // unspecified family
+        assert_eq!(parse_v2(0x21, 0x00, &[]).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_refused() {
+        let v1 = encode(ProxyVersion::V1, Some(addresses("192.0.2.1:1", "198.51.100.2:2")));
+        let v2 = encode(ProxyVersion::V2, Some(addresses("192.0.2.1:1", "198.51.100.2:2")));
+        for data in [&v1[..v1.len() - 2], &v2[..v2.len() - 3], &v2[..14], b"PROXY"] {
+            let (header, _) = read(data).await;
+            assert_eq!(header.unwrap_err().kind(), io::ErrorKind::UnexpectedEof, "{:?}", data);
+        }
+        // a length field shorter than the address block it announces
+        assert_eq!(parse_v2(0x21, 0x11, &v2[16..24]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_refused() {
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+        for data in [
+            b"GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_slice(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 1\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 1 99999\r\n",
+            long.as_bytes(),
+        ] {
+            let (header, _) = read(data).await;
+            assert_eq!(header.unwrap_err().kind(), io::ErrorKind::InvalidData, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn addresses_must_match_the_family() {
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2").is_err());
+        assert!(parse_v1(b"PROXY TCP6 192.0.2.1 198.51.100.2 1 2").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 2001:db8::2 1 2").is_err());
+        // an ipv4 block is too short to be read as ipv6
+        let v4 = encode(ProxyVersion::V2, Some(addresses("192.0.2.1:1", "198.51.100.2:2")));
+        assert!(parse_v2(0x21, 0x21, &v4[16..]).is_err());
+    }
+
+    #[test]
+    fn unknown_versions_and_commands_are_refused() {
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+        assert!(parse_v2(0x22, 0x11, &[0; 12]).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! byte range requests (rfc 9110 section 14) served from complete bodies,
+//! whether cached or read from the asset directory.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::header::{self, HeaderMap, HeaderValue};
+
+/// more ranges than this in one request are answered with the full body,
+/// which is cheaper than a pathological multipart response
+const MAX_RANGES: usize = 32;
+
+/// inclusive byte range within a body
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// `content-range` value for this range of a body of `total` bytes
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// how a request for a body of known length should be answered
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeOutcome {
+    /// no usable range: serve the whole body with its original status
+    Full,
+    /// serve these ranges with `206 partial content`
+    Partial(Vec<ByteRange>),
+    /// `416 range not satisfiable`
+    NotSatisfiable,
+}
+
+/// parses a `range` header against a body of `len` bytes. returns `None`
+/// when the header is malformed or uses a unit other than bytes, in which
+/// case it must be ignored; unsatisfiable specs are dropped from the result.
+pub fn parse_range(value: &str, len: u64) -> Option<Vec<ByteRange>> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim(), last.trim()) {
+            // suffix range: the final `n` bytes
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix > 0 && len > 0).then(|| ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                })
+            }
+            (first, last) => {
+                let start: u64 = first.parse().ok()?;
+                let end = match last {
+                    "" => u64::MAX,
+                    last => last.parse().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                (start < len).then(|| ByteRange {
+                    start,
+                    end: end.min(len - 1),
+                })
+            }
+        };
+        ranges.extend(range);
+    }
+    Some(ranges)
+}
+
+/// whether an `if-range` precondition still holds for the stored
+/// representation: a strong etag match, or an exact `last-modified` date
+fn if_range_matches(
+    if_range: &HeaderValue,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<&HeaderValue>,
+) -> bool {
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
+    };
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        // weak validators never match for ranges
+        let strong = |tag: &str| !tag.starts_with("W/");
+        return etag
+            .and_then(|etag| etag.to_str().ok())
+            .is_some_and(|etag| strong(if_range) && strong(etag) && etag == if_range);
+    }
+    let date = httpdate::parse_http_date(if_range).ok();
+    let last_modified = last_modified
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    date.is_some() && date == last_modified
+}
+
+/// decides how to answer a request for a complete body of `len` bytes whose
+/// validators are `etag` and `last_modified`
+pub fn evaluate(
+    request_headers: &HeaderMap,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<&HeaderValue>,
+    len: u64,
+) -> RangeOutcome {
+    let Some(range) = request_headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return RangeOutcome::Full;
+    };
+    if let Some(if_range) = request_headers.get(header::IF_RANGE) {
+        if !if_range_matches(if_range, etag, last_modified) {
+            return RangeOutcome::Full;
+        }
+    }
+    match parse_range(range, len) {
+        None => RangeOutcome::Full,
+        Some(ranges) if ranges.len() > MAX_RANGES => RangeOutcome::Full,
+        Some(ranges) if ranges.is_empty() => RangeOutcome::NotSatisfiable,
+        Some(ranges) => RangeOutcome::Partial(ranges),
+    }
+}
+
+/// `multipart/byteranges` body holding each range of `body` as its own part
+pub fn multipart_body(
+    ranges: &[ByteRange],
+    body: &Bytes,
+    content_type: Option<&HeaderValue>,
+    boundary: &str,
+) -> Bytes {
+    let total = body.len() as u64;
+    let mut out = BytesMut::new();
+    for range in ranges {
+        out.put_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+        if let Some(content_type) = content_type {
+            out.put_slice(b"content-type: ");
+            out.put_slice(content_type.as_bytes());
+            out.put_slice(b"\r\n");
+        }
+        out.put_slice(format!("content-range: {}\r\n\r\n", range.content_range(total)).as_bytes());
+        out.put_slice(&body[range.start as usize..=range.end as usize]);
+    }
+    out.put_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    out.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn request(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_body() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(vec![range(0, 9)]));
+        assert_eq!(parse_range("bytes=90-", 100), Some(vec![range(90, 99)]));
+        assert_eq!(parse_range("bytes=90-1000", 100), Some(vec![range(90, 99)]));
+        assert_eq!(parse_range("bytes=0-0, 5-9 ,", 100), Some(vec![range(0, 0), range(5, 9)]));
+        assert_eq!(parse_range("Bytes = 1-2", 100), Some(vec![range(1, 2)]));
+    }
+
+    #[test]
+    fn suffix_ranges_take_the_final_bytes() {
+        assert_eq!(parse_range("bytes=-10", 100), Some(vec![range(90, 99)]));
+        // longer than the body: all of it
+        assert_eq!(parse_range("bytes=-500", 100), Some(vec![range(0, 99)]));
+        assert_eq!(parse_range("bytes=-0", 100), Some(vec![]));
+        assert_eq!(parse_range("bytes=-10", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_dropped() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(vec![]));
+        assert_eq!(parse_range("bytes=200-300, 10-19", 100), Some(vec![range(10, 19)]));
+        assert_eq!(parse_range("bytes=0-", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        for value in ["items=0-9", "bytes", "bytes=9-0", "bytes=a-9", "bytes=0-9x", "bytes=5", "bytes=--5"] {
+            assert_eq!(parse_range(value, 100), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn evaluate_answers_ranges() {
+        let evaluate = |headers: &HeaderMap| evaluate(headers, None, None, 100);
+        assert_eq!(evaluate(&HeaderMap::new()), RangeOutcome::Full);
+        assert_eq!(
+            evaluate(&request(&[(header::RANGE, "bytes=0-9,-5")])),
+            RangeOutcome::Partial(vec![range(0, 9), range(95, 99)])
+        );
+        assert_eq!(evaluate(&request(&[(header::RANGE, "bytes=100-199")])), RangeOutcome::NotSatisfiable);
+        assert_eq!(evaluate(&request(&[(header::RANGE, "bytes=100-, 200-")])), RangeOutcome::NotSatisfiable);
+        assert_eq!(evaluate(&request(&[(header::RANGE, "lines=1-2")])), RangeOutcome::Full);
+
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(evaluate(&request(&[(header::RANGE, &many)])), RangeOutcome::Full);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_etag_match() {
+        let strong = HeaderValue::from_static("\"v1\"");
+        let weak = HeaderValue::from_static("W/\"v1\"");
+        let evaluate = |if_range: &str, etag: &HeaderValue| {
+            evaluate(
+                &request(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, if_range)]),
+                Some(etag),
+                None,
+                100,
+            )
+        };
+        assert_eq!(evaluate("\"v1\"", &strong), RangeOutcome::Partial(vec![range(0, 9)]));
+        assert_eq!(evaluate("\"v2\"", &strong), RangeOutcome::Full);
+        // weak validators never match, whichever side carries them
+        assert_eq!(evaluate("W/\"v1\"", &strong), RangeOutcome::Full);
+        assert_eq!(evaluate("W/\"v1\"", &weak), RangeOutcome::Full);
+        assert_eq!(evaluate("\"v1\"", &weak), RangeOutcome::Full);
+        // an if-range failing means the full body, even for an unsatisfiable range
+        let unsatisfiable = request(&[(header::RANGE, "bytes=500-"), (header::IF_RANGE, "\"v2\"")]);
+        assert_eq!(super::evaluate(&unsatisfiable, Some(&strong), None, 100), RangeOutcome::Full);
+    }
+
+    #[test]
+    fn if_range_dates_must_match_exactly() {
+        let last_modified = HeaderValue::from_static("Tue, 15 Nov 1994 08:12:31 GMT");
+        let evaluate = |if_range: &str| {
+            evaluate(
+                &request(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, if_range)]),
+                None,
+                Some(&last_modified),
+                100,
+            )
+        };
+        assert_eq!(evaluate("Tue, 15 Nov 1994 08:12:31 GMT"), RangeOutcome::Partial(vec![range(0, 9)]));
+        assert_eq!(evaluate("Tue, 15 Nov 1994 08:12:32 GMT"), RangeOutcome::Full);
+        assert_eq!(evaluate("yesterday"), RangeOutcome::Full);
+        // an etag cannot be compared against a date
+        assert_eq!(evaluate("\"v1\""), RangeOutcome::Full);
+    }
+
+    #[test]
+    fn multipart_parts_carry_their_ranges() {
+        let body = Bytes::from_static(b"0123456789");
+        let content_type = HeaderValue::from_static("text/plain");
+        let multipart = multipart_body(&[range(0, 1), range(8, 9)], &body, Some(&content_type), "b");
+        assert_eq!(
+            multipart,
+            Bytes::from_static(
+                b"\r\n--b\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/10\r\n\r\n01\
+                  \r\n--b\r\ncontent-type: text/plain\r\ncontent-range: bytes 8-9/10\r\n\r\n89\
+                  \r\n--b--\r\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! retries of failed origin requests.
+//!
+//! only idempotent requests without a body are retried, after connection
+//! errors or one of the configured statuses, with exponential backoff and
+//! full jitter. a retry budget caps retries at a share of all requests so
+//! that an origin in trouble does not get hit with a multiple of its load.
+
+use crate::config::Config;
+use crate::util::{Result, ShadowError};
+use http::{Method, StatusCode};
+use serde_json::{Map, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// retries always allowed per budget window, however few requests there were
+const MIN_RETRIES_PER_WINDOW: u32 = 10;
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// when and how often a request is retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// retries after the first attempt, 0 disables retrying
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub statuses: Vec<StatusCode>,
+    /// retries allowed as a share of requests
+    pub budget_ratio: f64,
+}
+
+fn config_error(message: impl Into<String>) -> ShadowError {
+    ShadowError::Config(format!("retry: {}", message.into()))
+}
+
+impl RetryPolicy {
+    /// the policy given on the command line
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let statuses = config
+            .origin_retry_statuses
+            .iter()
+            .map(|status| StatusCode::from_u16(*status).map_err(|_| config_error(format!("invalid status {}", status))))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            attempts: config.origin_retries,
+            backoff: Duration::from_millis(config.origin_retry_backoff_ms),
+            max_backoff: Duration::from_millis(config.origin_retry_max_backoff_ms),
+            statuses,
+            budget_ratio: config.origin_retry_budget_percent as f64 / 100.0,
+        })
+    }
+
+    /// the policy with an origin's `retry` options laid over it
+    pub fn with_json(mut self, options: &Map<String, Value>) -> Result<Self> {
+        for (option, value) in options {
+            let number = || {
+                value
+                    .as_u64()
+                    .ok_or_else(|| config_error(format!("`{}` must be a number", option)))
+            };
+            match option.as_str() {
+                "attempts" => self.attempts = number()? as u32,
+                "backoff_ms" => self.backoff = Duration::from_millis(number()?),
+                "max_backoff_ms" => self.max_backoff = Duration::from_millis(number()?),
+                "budget_percent" => self.budget_ratio = number()? as f64 / 100.0,
+                "statuses" => {
+                    self.statuses = value
+                        .as_array()
+                        .and_then(|statuses| {
+                            statuses
+                                .iter()
+                                .map(|s| StatusCode::from_u16(u16::try_from(s.as_u64()?).ok()?).ok())
+                                .collect()
+                        })
+                        .ok_or_else(|| config_error("`statuses` must be a list of status codes"))?
+                }
+                _ => return Err(config_error(format!("unknown option `{}`", option))),
+            }
+        }
+        Ok(self)
+    }
+
+    /// whether requests with this method may be sent more than once
+    pub fn is_idempotent(method: &Method) -> bool {
+        matches!(
+            *method,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+        )
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// delay before retry number `retry` (starting at 1): a random duration
+    /// up to the exponentially growing backoff
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        let random = RandomState::new().build_hasher().finish();
+        ceiling.mul_f64((random % 1000) as f64 / 1000.0)
+    }
+}
+
+/// counts of one fixed budget window
+#[derive(Clone, Copy, Debug, Default)]
+struct BudgetCounts {
+    requests: u32,
+    retries: u32,
+}
+
+#[derive(Debug)]
+struct BudgetWindow {
+    started: Instant,
+    current: BudgetCounts,
+    previous: BudgetCounts,
+}
+
+impl BudgetWindow {
+    /// moves on to the window `now` falls into
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed >= 2 * BUDGET_WINDOW {
+            self.started = now;
+            self.previous = BudgetCounts::default();
+            self.current = BudgetCounts::default();
+        } else if elapsed >= BUDGET_WINDOW {
+            self.started += BUDGET_WINDOW;
+            self.previous = self.current;
+            self.current = BudgetCounts::default();
+        }
+    }
+
+    /// requests and retries over the last `BUDGET_WINDOW`, counting the
+    /// previous window by how much of it still overlaps
+    fn sliding(&self, now: Instant) -> (f64, f64) {
+        let elapsed = now.saturating_duration_since(self.started);
+        let overlap = 1.0 - (elapsed.as_secs_f64() / BUDGET_WINDOW.as_secs_f64()).min(1.0);
+        (
+            self.current.requests as f64 + self.previous.requests as f64 * overlap,
+            self.current.retries as f64 + self.previous.retries as f64 * overlap,
+        )
+    }
+}
+
+/// caps retries at a share of the requests seen over a sliding window,
+/// estimated from the current fixed window and the weighted previous one
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    window: Mutex<BudgetWindow>,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> Self {
+        Self {
+            ratio,
+            window: Mutex::new(BudgetWindow {
+                started: Instant::now(),
+                current: BudgetCounts::default(),
+                previous: BudgetCounts::default(),
+            }),
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.record_request_at(Instant::now());
+    }
+
+    fn record_request_at(&self, now: Instant) {
+        let mut window = self.window.lock().unwrap();
+        window.roll(now);
+        window.current.requests += 1;
+    }
+
+    /// takes a retry from the budget, false when it is spent
+    pub fn try_retry(&self) -> bool {
+        self.try_retry_at(Instant::now())
+    }
+
+    fn try_retry_at(&self, now: Instant) -> bool {
+        let mut window = self.window.lock().unwrap();
+        window.roll(now);
+        let (requests, retries) = window.sliding(now);
+        let allowed = (MIN_RETRIES_PER_WINDOW as f64).max(requests * self.ratio);
+        if retries + 1.0 <= allowed {
+            window.current.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 2,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(250),
+            statuses: vec![StatusCode::BAD_GATEWAY],
+            budget_ratio: 0.2,
+        }
+    }
+
+    #[test]
+    fn backoff_is_jittered_below_an_exponential_ceiling() {
+        let policy = policy();
+        for _ in 0..100 {
+            assert!(policy.backoff(1) < Duration::from_millis(100));
+            assert!(policy.backoff(2) < Duration::from_millis(200));
+            assert!(policy.backoff(3) < Duration::from_millis(250));
+            assert!(policy.backoff(40) < Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn only_idempotent_methods_are_retried() {
+        assert!(RetryPolicy::is_idempotent(&Method::GET));
+        assert!(RetryPolicy::is_idempotent(&Method::PUT));
+        assert!(RetryPolicy::is_idempotent(&Method::DELETE));
+        assert!(!RetryPolicy::is_idempotent(&Method::POST));
+        assert!(!RetryPolicy::is_idempotent(&Method::PATCH));
+    }
+
+    #[test]
+    fn origin_options_override_the_policy() {
+        let options = json!({ "attempts": 5, "backoff_ms": 10, "statuses": [503, 504] });
+        let policy = policy().with_json(options.as_object().unwrap()).unwrap();
+        assert_eq!(policy.attempts, 5);
+        assert_eq!(policy.backoff, Duration::from_millis(10));
+        assert!(policy.retries_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!policy.retries_status(StatusCode::BAD_GATEWAY));
+
+        assert!(policy.clone().with_json(json!({ "statuses": [42] }).as_object().unwrap()).is_err());
+        assert!(policy.with_json(json!({ "tries": 1 }).as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn budget_allows_a_minimum_and_then_a_share_of_requests() {
+        let budget = RetryBudget::new(0.2);
+        let now = Instant::now();
+        let retries = |budget: &RetryBudget| (0..1000).take_while(|_| budget.try_retry_at(now)).count();
+        assert_eq!(retries(&budget), MIN_RETRIES_PER_WINDOW as usize);
+
+        let budget = RetryBudget::new(0.2);
+        for _ in 0..100 {
+            budget.record_request_at(now);
+        }
+        assert_eq!(retries(&budget), 20);
+    }
+
+    #[test]
+    fn budget_window_slides_across_boundaries() {
+        let budget = RetryBudget::new(0.2);
+        let start = budget.window.lock().unwrap().started;
+        for _ in 0..100 {
+            budget.record_request_at(start);
+        }
+        let late = start + BUDGET_WINDOW - Duration::from_millis(1);
+        assert_eq!((0..100).take_while(|_| budget.try_retry_at(late)).count(), 20);
+
+        // past the boundary the spent budget still weighs by its overlap,
+        // where a fixed window would hand out a fresh minimum right away
+        assert!(!budget.try_retry_at(start + BUDGET_WINDOW + BUDGET_WINDOW / 10));
+        assert!(!budget.try_retry_at(start + BUDGET_WINDOW + BUDGET_WINDOW / 2));
+        let three_quarters = start + BUDGET_WINDOW + BUDGET_WINDOW * 3 / 4;
+        assert_eq!((0..100).take_while(|_| budget.try_retry_at(three_quarters)).count(), 5);
+
+        // once the previous window has slid out, only the current one counts
+        let next = start + 2 * BUDGET_WINDOW + Duration::from_millis(1);
+        assert!(budget.try_retry_at(next));
+        let gone = start + 4 * BUDGET_WINDOW;
+        assert_eq!((0..100).take_while(|_| budget.try_retry_at(gone)).count(), MIN_RETRIES_PER_WINDOW as usize);
+    }
+}
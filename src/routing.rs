@@ -0,0 +1,567 @@
+//! routing of requests to named origins.
+//!
+//! without a routing file every request goes to `ORIGIN_URL`. a json file
+//! given with `ORIGINS_CONFIG` declares several origins and the routes that
+//! select them:
+//!
+//! ```json
+//! {
+//!   "origins": {
+//!     "web": { "url": "https://web.internal" },
+//!     "api": { "url": "http://api.internal:8080", "cache": false, "first_byte_timeout_ms": 5000 },
+//!     "media": { "url": "http://media.internal", "ttl_seconds": 86400, "connect_timeout_ms": 1000 },
+//!     "app": {
+//!       "servers": ["http://10.0.0.1:8080", { "url": "http://10.0.0.2:8080", "weight": 2 }],
+//!       "balance": "least_connections"
+//!     }
+//!   },
+//!   "routes": [
+//!     { "host": "api.example.com", "origin": "api" },
+//!     { "path_prefix": "/media/", "methods": ["GET", "HEAD"], "origin": "media" },
+//!     { "path_regex": "^/v[0-9]+/", "origin": "api" }
+//!   ],
+//!   "default": "web"
+//! }
+//! ```
+//!
+//! the first route whose conditions all match picks the origin; requests no
+//! route matches go to `default`, or to `ORIGIN_URL` when that is left out.
+//! `host` is matched case-insensitively, without the port, and may be a glob
+//! (`*.example.com`).
+//!
+//! an origin is either one `url` or a pool of `servers`, balanced with
+//! `round_robin` (the default), `least_connections`, `weighted` or
+//! `consistent_hash` on the cache key. servers are probed when the origin
+//! has a `health_check` (see [`crate::health`]) and ejected after failing
+//! requests as set by `outlier_detection`, by default
+//! `{ "consecutive_errors": 5, "ejection_ms": 30000 }`. a `circuit_breaker`
+//! (see [`crate::breaker`]) makes requests fail fast while the origin as a
+//! whole is failing.
+//!
+//! timeouts and retries default to the command line settings and can be set
+//! per origin with `connect_timeout_ms`, `first_byte_timeout_ms`,
+//! `total_timeout_ms` (0 waits indefinitely) and a `retry` object, e.g.
+//! `{ "attempts": 3, "backoff_ms": 100, "statuses": [503] }` (see
+//! [`crate::retry`]). `proxy_protocol` (`v1`, `v2` or `none`) overrides
+//! whether PROXY headers are sent (see [`crate::proxy_protocol`]), and
+//! `protocol` (`http1`, `auto` or `http2`) the http version spoken.
+
+use crate::breaker::{BreakerConfig, CircuitBreaker};
+use crate::cache::glob_matches;
+use crate::config::Config;
+use crate::fetcher::{OriginFetcher, OriginProtocol, OriginTimeouts};
+use crate::health::HealthCheck;
+use crate::pool::{Balance, OriginPool, OutlierDetection, Upstream};
+use crate::proxy_protocol::ProxyVersion;
+use crate::retry::RetryPolicy;
+use crate::util::{Result, ShadowError};
+use http::{Method, StatusCode};
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// name of the origin built from `ORIGIN_URL`
+pub const DEFAULT_ORIGIN: &str = "default";
+
+/// an upstream service with its own client, cache policy and timeouts
+pub struct Origin {
+    pub name: String,
+    pub fetcher: OriginFetcher,
+    /// responses from this origin are cached at all
+    pub cache: bool,
+    /// ttl of successful responses without freshness information, overriding
+    /// the global default
+    pub ttl: Option<Duration>,
+    /// active probing of the origin's servers, `None` when not configured
+    pub health_check: Option<HealthCheck>,
+}
+
+fn config_error(message: impl Into<String>) -> ShadowError {
+    ShadowError::Config(format!("origins: {}", message.into()))
+}
+
+fn millis(options: &Map<String, Value>, option: &str) -> Result<Option<Duration>> {
+    options
+        .get(option)
+        .map(|value| {
+            value
+                .as_u64()
+                .map(Duration::from_millis)
+                .ok_or_else(|| config_error(format!("`{}` must be a number of milliseconds", option)))
+        })
+        .transpose()
+}
+
+/// servers of a pool, each a url or `{ "url": ..., "weight": n }`
+fn upstreams(origin: &str, servers: &Value) -> Result<Vec<Upstream>> {
+    let invalid = || config_error(format!("`servers` of origin `{}` must be a list of urls or {{url, weight}} objects", origin));
+    let servers = servers.as_array().ok_or_else(invalid)?;
+    servers
+        .iter()
+        .map(|server| match server {
+            Value::String(url) => Upstream::new(url, 1),
+            Value::Object(server) => {
+                let url = server.get("url").and_then(Value::as_str).ok_or_else(invalid)?;
+                let weight = match server.get("weight") {
+                    Some(weight) => weight.as_u64().and_then(|w| u32::try_from(w).ok()).ok_or_else(invalid)?,
+                    None => 1,
+                };
+                Upstream::new(url, weight)
+            }
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+fn outlier_detection(options: &Map<String, Value>) -> Result<OutlierDetection> {
+    let mut outliers = OutlierDetection::default();
+    for (option, value) in options {
+        let number = value
+            .as_u64()
+            .ok_or_else(|| config_error(format!("`{}` must be a number", option)));
+        match option.as_str() {
+            "consecutive_errors" => outliers.consecutive_errors = number? as u32,
+            "ejection_ms" => outliers.ejection = Duration::from_millis(number?),
+            _ => return Err(config_error(format!("unknown outlier_detection option `{}`", option))),
+        }
+    }
+    Ok(outliers)
+}
+
+impl Origin {
+    fn from_json(name: &str, options: &Map<String, Value>, config: &Config) -> Result<Self> {
+        for option in options.keys() {
+            if !matches!(
+                option.as_str(),
+                "url"
+                    | "servers"
+                    | "balance"
+                    | "health_check"
+                    | "outlier_detection"
+                    | "circuit_breaker"
+                    | "cache"
+                    | "ttl_seconds"
+                    | "connect_timeout_ms"
+                    | "first_byte_timeout_ms"
+                    | "total_timeout_ms"
+                    | "retry"
+                    | "proxy_protocol"
+                    | "protocol"
+            ) {
+                return Err(config_error(format!("unknown option `{}` for origin `{}`", option, name)));
+            }
+        }
+        let outliers = match options.get("outlier_detection") {
+            Some(outliers) => outlier_detection(
+                outliers
+                    .as_object()
+                    .ok_or_else(|| config_error("`outlier_detection` must be an object"))?,
+            )?,
+            None => OutlierDetection::default(),
+        };
+        let pool = match (options.get("url"), options.get("servers")) {
+            (Some(url), None) => {
+                let url = url.as_str().ok_or_else(|| config_error("`url` must be a string"))?;
+                OriginPool::new(vec![Upstream::new(url, 1)?], Balance::RoundRobin, outliers)?
+            }
+            (None, Some(servers)) => {
+                let balance = match options.get("balance") {
+                    Some(balance) => Balance::parse(
+                        balance.as_str().ok_or_else(|| config_error("`balance` must be a string"))?,
+                    )?,
+                    None => Balance::RoundRobin,
+                };
+                OriginPool::new(upstreams(name, servers)?, balance, outliers)?
+            }
+            _ => return Err(config_error(format!("origin `{}` needs either a `url` or `servers`", name))),
+        };
+        let cache = match options.get("cache") {
+            Some(cache) => cache
+                .as_bool()
+                .ok_or_else(|| config_error("`cache` must be true or false"))?,
+            None => true,
+        };
+        let ttl = options
+            .get("ttl_seconds")
+            .map(|ttl| {
+                ttl.as_u64()
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| config_error("`ttl_seconds` must be a number"))
+            })
+            .transpose()?;
+        let health_check = options
+            .get("health_check")
+            .map(|check| {
+                check
+                    .as_object()
+                    .ok_or_else(|| config_error("`health_check` must be an object"))
+                    .and_then(HealthCheck::from_json)
+            })
+            .transpose()?;
+        let breaker = options
+            .get("circuit_breaker")
+            .map(|breaker| {
+                breaker
+                    .as_object()
+                    .ok_or_else(|| config_error("`circuit_breaker` must be an object"))
+                    .and_then(BreakerConfig::from_json)
+                    .map(|config| CircuitBreaker::new(name, config))
+            })
+            .transpose()?;
+        // an origin's own timeout replaces the default, 0 disables it
+        let defaults = OriginTimeouts::from_config(config);
+        let timeout = |option: &str, default: Option<Duration>| -> Result<Option<Duration>> {
+            Ok(match millis(options, option)? {
+                Some(limit) => (!limit.is_zero()).then_some(limit),
+                None => default,
+            })
+        };
+        let timeouts = OriginTimeouts {
+            connect: timeout("connect_timeout_ms", defaults.connect)?,
+            first_byte: timeout("first_byte_timeout_ms", defaults.first_byte)?,
+            total: timeout("total_timeout_ms", defaults.total)?,
+        };
+        let retry = match options.get("retry") {
+            Some(retry) => RetryPolicy::from_config(config)?.with_json(
+                retry
+                    .as_object()
+                    .ok_or_else(|| config_error("`retry` must be an object"))?,
+            )?,
+            None => RetryPolicy::from_config(config)?,
+        };
+        let proxy_protocol = match options.get("proxy_protocol") {
+            Some(version) => ProxyVersion::parse(
+                version
+                    .as_str()
+                    .ok_or_else(|| config_error("`proxy_protocol` must be v1, v2 or none"))?,
+            )?,
+            None => config.origin_proxy_protocol,
+        };
+        let protocol = match options.get("protocol") {
+            Some(protocol) => OriginProtocol::parse(
+                protocol
+                    .as_str()
+                    .ok_or_else(|| config_error("`protocol` must be http1, auto or http2"))?,
+            )?,
+            None => config.origin_protocol,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            fetcher: OriginFetcher::new(pool, timeouts, retry, breaker, proxy_protocol, protocol)?,
+            cache,
+            ttl,
+            health_check,
+        })
+    }
+
+    /// ttl used when a response carries no freshness information, given the
+    /// cache-wide default for its status; `None` if it is not cached
+    pub fn default_ttl_for(&self, status: StatusCode, global: Option<Duration>) -> Option<Duration> {
+        if !self.cache {
+            return None;
+        }
+        match self.ttl {
+            Some(ttl) if status.is_success() && status != StatusCode::PARTIAL_CONTENT => {
+                (!ttl.is_zero()).then_some(ttl)
+            }
+            _ => global,
+        }
+    }
+}
+
+/// conditions a request must meet for a route to apply
+struct Route {
+    host: Option<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<Method>,
+    origin: Arc<Origin>,
+}
+
+impl Route {
+    fn from_json(route: &Map<String, Value>, origins: &HashMap<String, Arc<Origin>>) -> Result<Self> {
+        let string = |option: &str| -> Result<Option<String>> {
+            route
+                .get(option)
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| config_error(format!("`{}` must be a string", option)))
+                })
+                .transpose()
+        };
+        for option in route.keys() {
+            if !matches!(option.as_str(), "host" | "path_prefix" | "path_regex" | "methods" | "origin") {
+                return Err(config_error(format!("unknown route option `{}`", option)));
+            }
+        }
+
+        let name = string("origin")?.ok_or_else(|| config_error("every route needs an `origin`"))?;
+        let origin = origins
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| config_error(format!("route refers to unknown origin `{}`", name)))?;
+        let path_regex = string("path_regex")?
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()
+            .map_err(|e| config_error(format!("invalid `path_regex`: {}", e)))?;
+        let methods = match route.get("methods") {
+            Some(methods) => methods
+                .as_array()
+                .and_then(|methods| {
+                    methods
+                        .iter()
+                        .map(|m| m.as_str()?.to_ascii_uppercase().parse::<Method>().ok())
+                        .collect()
+                })
+                .ok_or_else(|| config_error("`methods` must be a list of http methods"))?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            host: string("host")?.map(|host| host.to_ascii_lowercase()),
+            path_prefix: string("path_prefix")?,
+            path_regex,
+            methods,
+            origin,
+        })
+    }
+
+    fn matches(&self, method: &Method, host: &str, path: &str) -> bool {
+        self.host.as_deref().is_none_or(|pattern| glob_matches(pattern, host))
+            && self.path_prefix.as_deref().is_none_or(|prefix| path.starts_with(prefix))
+            && self.path_regex.as_ref().is_none_or(|regex| regex.is_match(path))
+            && (self.methods.is_empty() || self.methods.contains(method))
+    }
+}
+
+/// picks the origin for each request
+pub struct Router {
+    origins: HashMap<String, Arc<Origin>>,
+    routes: Vec<Route>,
+    default: Arc<Origin>,
+}
+
+impl Router {
+    /// builds the routing table from `ORIGINS_CONFIG`, falling back to a
+    /// single origin at `ORIGIN_URL`
+    pub fn new(config: &Config) -> Result<Self> {
+        let single = || -> Result<Arc<Origin>> {
+            let url = config
+                .origin_url
+                .as_deref()
+                .ok_or_else(|| ShadowError::Config("ORIGIN_URL or ORIGINS_CONFIG is required".into()))?;
+            Ok(Arc::new(Origin {
+                name: DEFAULT_ORIGIN.to_string(),
+                fetcher: OriginFetcher::new(
+                    OriginPool::single(url)?,
+                    OriginTimeouts::from_config(config),
+                    RetryPolicy::from_config(config)?,
+                    BreakerConfig::from_config(config)?.map(|breaker| CircuitBreaker::new(DEFAULT_ORIGIN, breaker)),
+                    config.origin_proxy_protocol,
+                    config.origin_protocol,
+                )?,
+                cache: true,
+                ttl: None,
+                health_check: HealthCheck::from_config(config)?,
+            }))
+        };
+        match &config.origins_config {
+            Some(path) => Self::load(path, config, single),
+            None => {
+                let origin = single()?;
+                Ok(Self {
+                    origins: HashMap::from([(origin.name.clone(), origin.clone())]),
+                    routes: Vec::new(),
+                    default: origin,
+                })
+            }
+        }
+    }
+
+    fn load(path: &Path, config: &Config, fallback: impl FnOnce() -> Result<Arc<Origin>>) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        let value: Value = serde_json::from_slice(&data)
+            .map_err(|e| config_error(format!("{:?} is not valid json: {}", path, e)))?;
+        let table = value.as_object().ok_or_else(|| config_error("must be a json object"))?;
+        if let Some(section) = table.keys().find(|k| !matches!(k.as_str(), "origins" | "routes" | "default")) {
+            return Err(config_error(format!("unknown section `{}`", section)));
+        }
+
+        let mut origins = HashMap::new();
+        let declared = table
+            .get("origins")
+            .and_then(Value::as_object)
+            .ok_or_else(|| config_error("`origins` must be an object of named origins"))?;
+        for (name, options) in declared {
+            let options = options
+                .as_object()
+                .ok_or_else(|| config_error(format!("origin `{}` must be an object", name)))?;
+            origins.insert(name.clone(), Arc::new(Origin::from_json(name, options, config)?));
+        }
+
+        let mut routes = Vec::new();
+        for route in table.get("routes").and_then(Value::as_array).into_iter().flatten() {
+            let route = route
+                .as_object()
+                .ok_or_else(|| config_error("every route must be an object"))?;
+            routes.push(Route::from_json(route, &origins)?);
+        }
+
+        let default = match table.get("default") {
+            Some(name) => {
+                let name = name.as_str().ok_or_else(|| config_error("`default` must be an origin name"))?;
+                origins
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| config_error(format!("default origin `{}` is not declared", name)))?
+            }
+            None => {
+                let origin = fallback()?;
+                origins.entry(origin.name.clone()).or_insert(origin).clone()
+            }
+        };
+
+        Ok(Self {
+            origins,
+            routes,
+            default,
+        })
+    }
+
+    /// origin serving a request
+    pub fn route(&self, method: &Method, host: &str, path: &str) -> &Arc<Origin> {
+        let host = host.to_ascii_lowercase();
+        // ports play no part in routing; ipv6 literals keep their brackets
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !name.is_empty() && !port.contains(']') => name,
+            _ => host.as_str(),
+        };
+        self.routes
+            .iter()
+            .find(|route| route.matches(method, host, path))
+            .map_or(&self.default, |route| &route.origin)
+    }
+
+    /// whether any route selects its origin by host
+    pub fn routes_by_host(&self) -> bool {
+        self.routes.iter().any(|route| route.host.is_some())
+    }
+
+    /// all configured origins
+    pub fn origins(&self) -> impl Iterator<Item = &Arc<Origin>> {
+        self.origins.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use serde_json::json;
+
+    fn router(table: Value) -> Result<Router> {
+        let path = std::env::temp_dir().join(format!(
+            "shadowstep-origins-{}-{:?}.json",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, table.to_string())?;
+        let config = Config::parse_from(["shadowstep", "--origin-url", "http://fallback.internal"]);
+        let router = Router::new(&Config {
+            origins_config: Some(path.clone()),
+            ..config
+        });
+        let _ = std::fs::remove_file(&path);
+        router
+    }
+
+    fn routes() -> Router {
+        router(json!({
+            "origins": {
+                "web": { "url": "https://web.internal" },
+                "api": { "url": "http://api.internal:8080", "cache": false },
+                "media": { "url": "http://media.internal", "ttl_seconds": 86400 },
+                "tenants": { "url": "http://tenants.internal" }
+            },
+            "routes": [
+                { "host": "api.example.com", "origin": "api" },
+                { "host": "*.tenants.example.com", "origin": "tenants" },
+                { "path_prefix": "/media/", "methods": ["get", "HEAD"], "origin": "media" },
+                { "path_regex": "^/v[0-9]+/", "origin": "api" }
+            ],
+            "default": "web"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn first_matching_route_picks_the_origin() {
+        let router = routes();
+        let origin = |method: Method, host: &str, path: &str| router.route(&method, host, path).name.clone();
+        assert_eq!(origin(Method::GET, "API.example.com:8443", "/media/a.png"), "api");
+        assert_eq!(origin(Method::GET, "acme.tenants.example.com", "/"), "tenants");
+        assert_eq!(origin(Method::GET, "tenants.example.com", "/"), "web");
+        assert_eq!(origin(Method::HEAD, "www.example.com", "/media/a.png"), "media");
+        assert_eq!(origin(Method::POST, "www.example.com", "/media/a.png"), "web");
+        assert_eq!(origin(Method::POST, "www.example.com", "/v2/users"), "api");
+        assert_eq!(origin(Method::GET, "[::1]:8080", "/"), "web");
+        assert!(router.routes_by_host());
+    }
+
+    #[test]
+    fn origins_carry_their_cache_policy() {
+        let router = routes();
+        let origin = |name: &str| router.origins().find(|o| o.name == name).unwrap().clone();
+        let day = Some(Duration::from_secs(86400));
+        let global = Some(Duration::from_secs(300));
+        assert_eq!(origin("media").default_ttl_for(StatusCode::OK, global), day);
+        assert_eq!(origin("media").default_ttl_for(StatusCode::NOT_FOUND, global), global);
+        assert_eq!(origin("web").default_ttl_for(StatusCode::OK, global), global);
+        assert_eq!(origin("api").default_ttl_for(StatusCode::OK, global), None);
+    }
+
+    #[test]
+    fn default_falls_back_to_the_origin_url() {
+        let router = router(json!({
+            "origins": { "api": { "url": "http://api.internal" } },
+            "routes": [{ "path_prefix": "/api/", "origin": "api" }]
+        }))
+        .unwrap();
+        assert_eq!(router.route(&Method::GET, "example.com", "/").name, DEFAULT_ORIGIN);
+        assert_eq!(router.route(&Method::GET, "example.com", "/api/x").name, "api");
+        assert!(!router.routes_by_host());
+    }
+
+    #[test]
+    fn origin_url_takes_its_breaker_from_the_command_line() {
+        let single = |args: &[&str]| {
+            let config = Config::parse_from(["shadowstep", "--origin-url", "http://origin.internal"].iter().chain(args));
+            Router::new(&config).unwrap()
+        };
+        let router = single(&[]);
+        assert!(router.route(&Method::GET, "example.com", "/").fetcher.breaker().is_none());
+        let router = single(&["--origin-circuit-breaker", "true"]);
+        assert!(router.route(&Method::GET, "example.com", "/").fetcher.breaker().is_some());
+    }
+
+    #[test]
+    fn invalid_tables_are_refused() {
+        let origins = json!({ "web": { "url": "http://web.internal" } });
+        for table in [
+            json!({ "origins": origins, "routes": [{ "origin": "missing" }] }),
+            json!({ "origins": origins, "routes": [{ "path": "/", "origin": "web" }] }),
+            json!({ "origins": origins, "routes": [{ "path_regex": "(", "origin": "web" }] }),
+            json!({ "origins": origins, "routes": [{ "methods": "GET", "origin": "web" }] }),
+            json!({ "origins": origins, "default": "missing" }),
+            json!({ "origins": origins, "extra": {} }),
+            json!({ "origins": { "web": { "url": "http://a", "servers": ["http://b"] } } }),
+            json!({ "origins": { "web": { "url": "http://a", "colour": "red" } } }),
+        ] {
+            assert!(router(table.clone()).is_err(), "{}", table);
+        }
+    }
+}
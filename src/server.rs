@@ -1,37 +1,277 @@
-use crate::cache::{CachedResponse, CdnCache};
+use crate::admin;
+use crate::body::{self, Limited, TeeBody};
+use crate::cache::{self, CachedResponse, CdnCache, StatusClass};
+use crate::cache_key::CacheKeyPolicy;
+use crate::coalesce::{Coalescer, Leader, Role};
 use crate::config::Config;
-use crate::fetcher::OriginFetcher;
+use crate::forwarding::{self, Forwarding};
+use crate::freshness::{self, CacheControl, StaleWindows};
+use crate::health;
+use crate::listener::{self, Connection};
+use crate::proxy_protocol::ProxyAddresses;
+use crate::range::{self, RangeOutcome};
+use crate::routing::{Origin, Router};
 use crate::tls::load_rustls_config;
+use crate::tunnel::{self, TunnelStats, UpgradeProtocol};
 use crate::util::{Result, ShadowError};
 
 use actix_web::{
-    dev::Payload,
-    middleware::Logger, // provides access logging
+    body::SizedStream,
+    get,
+    middleware::Compress, // compress provides gzip/br
     web,
     App,
     HttpRequest,
     HttpResponse,
-    HttpServer,
     Responder,
 };
 use bytes::Bytes;
+use futures_util::StreamExt;
 use http::{
-    header::{HeaderName, HeaderValue},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
     Version as HttpVersion,
 };
 use log::{debug, error, info, warn};
-use std::sync::Arc;
-use std::time::Instant;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 struct AppState {
-    fetcher: Arc<OriginFetcher>,
+    /// origins and the routes selecting them
+    router: Router,
     cache: Arc<CdnCache>,
+    asset_path: PathBuf,
+    /// cache keys with a stale-while-revalidate refresh in flight
+    refreshing: Mutex<HashSet<String>>,
+    /// in-flight origin fetches shared by concurrent misses, `None` when disabled
+    coalescer: Option<Arc<Coalescer<SharedFetch>>>,
+    /// fetch full objects on a range miss instead of passing the range on
+    range_fetch_full: bool,
+    /// largest request body forwarded to the origin in bytes, `None` for no limit
+    max_request_body: Option<u64>,
+    /// how the client is described to origins
+    forwarding: Forwarding,
+    /// websocket tunnels close after this long without data, `None` for never
+    tunnel_idle_timeout: Option<Duration>,
+    tunnels: Arc<TunnelStats>,
     // config: Arc<Config>, // config is cloned per-server thread by actix, or app_config can be used directly
 }
 
-async fn actix_to_hyper_request(
+/// outcome of an origin fetch, shareable between coalesced requests
+type OriginResult = std::result::Result<Arc<http::Response<Bytes>>, FetchError>;
+
+/// origin fetch failure in a form that can be cloned and sent across workers
+#[derive(Clone, Debug)]
+struct FetchError {
+    message: Arc<str>,
+    /// the connection to the origin failed, as opposed to e.g. a bad request uri
+    transport: bool,
+    /// the origin did not connect or answer in time
+    timed_out: bool,
+    /// set when the origin's circuit breaker is open: when it will be tried again
+    retry_after: Option<Duration>,
+}
+
+impl From<ShadowError> for FetchError {
+    fn from(e: ShadowError) -> Self {
+        Self {
+            transport: matches!(
+                e,
+                ShadowError::Hyper(_)
+                    | ShadowError::OriginTimeout(_)
+                    | ShadowError::ConnectTimeout(_)
+                    | ShadowError::CircuitOpen(_)
+            ),
+            timed_out: matches!(e, ShadowError::OriginTimeout(_) | ShadowError::ConnectTimeout(_)),
+            retry_after: match e {
+                ShadowError::CircuitOpen(retry_after) => Some(retry_after),
+                _ => None,
+            },
+            message: e.to_string().into(),
+        }
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// origin fetch result handed to coalesced followers, along with the request
+/// headers the leader fetched it with
+#[derive(Clone)]
+struct SharedFetch {
+    result: OriginResult,
+    request_headers: Arc<HeaderMap>,
+}
+
+impl SharedFetch {
+    /// whether a follower with the given request headers may use the leader's
+    /// result: the response must be one a shared cache could store, match the
+    /// follower on every `vary` field, and a `304` is only useful to a
+    /// follower holding the stale entry it refers to
+    fn usable_for(&self, request_headers: &HeaderMap, has_stale: bool) -> bool {
+        let Ok(response) = &self.result else {
+            return true;
+        };
+        if response.status() == StatusCode::NOT_MODIFIED && !has_stale {
+            return false;
+        }
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            return false;
+        }
+        let storable = freshness::is_storable(
+            &CacheControl::from_headers(&self.request_headers),
+            self.request_headers.contains_key(header::AUTHORIZATION),
+            response.headers(),
+        );
+        if !storable {
+            return false;
+        }
+        match freshness::vary_fields(response.headers()) {
+            Some(fields) => fields.iter().all(|name| {
+                self.request_headers.get_all(name).iter().eq(request_headers.get_all(name).iter())
+            }),
+            None => false,
+        }
+    }
+}
+
+/// body of an origin response on its way to the client
+enum OriginBody {
+    /// complete body of a fetch shared by another request
+    Buffered(Bytes),
+    /// body streamed from the origin as it arrives
+    Streaming(hyper::Body),
+}
+
+/// origin response for one client request. `leader` is set when concurrent
+/// requests wait on this fetch; they are handed the response once its body
+/// is complete.
+struct Fetched {
+    response: http::Response<OriginBody>,
+    leader: Option<Leader<SharedFetch>>,
+}
+
+/// copy of a response head with a different body
+fn response_with_body<A, B>(head: &http::Response<A>, body: B) -> http::Response<B> {
+    let mut response = http::Response::new(body);
+    *response.status_mut() = head.status();
+    *response.version_mut() = head.version();
+    *response.headers_mut() = head.headers().clone();
+    response
+}
+
+/// hands a complete origin response to the requests waiting on this fetch
+fn share(
+    leader: Option<Leader<SharedFetch>>,
+    request_headers: &HeaderMap,
+    response: http::Response<Bytes>,
+) {
+    if let Some(leader) = leader {
+        leader.complete(SharedFetch {
+            result: Ok(Arc::new(response)),
+            request_headers: Arc::new(request_headers.clone()),
+        });
+    }
+}
+
+/// reads a body that is not passed on to the client; a failed read leaves it empty
+async fn discard_body(body: OriginBody) -> Bytes {
+    match body {
+        OriginBody::Buffered(bytes) => bytes,
+        OriginBody::Streaming(body) => hyper::body::to_bytes(body).await.unwrap_or_default(),
+    }
+}
+
+/// copies the incoming request headers into an `http::HeaderMap`, which is
+/// what the cache works with
+fn request_header_map(req: &HttpRequest) -> HeaderMap {
+    let mut headers = HeaderMap::with_capacity(req.headers().len());
+    for (name, value) in req.headers() {
+        headers.append(name.clone(), value.clone());
+    }
+    headers
+}
+
+/// returns true when the request carries a body that has to be forwarded.
+/// http/2 frames the body itself and need not declare its length, so its
+/// payload is always streamed unless the request says it is empty.
+fn has_request_body(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return true;
+    }
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match declared {
+        Some(len) => len > 0,
+        None => req.version() == actix_web::http::Version::HTTP_2,
+    }
+}
+
+/// checks the request head before anything is read or forwarded. a body
+/// declared larger than the limit is refused right away, and expectations
+/// other than `100-continue` (which the listener answers, see
+/// [`listener`]) cannot be met, nor upgrades that cannot be tunnelled.
+fn check_request_head(req: &HttpRequest, max_body: Option<u64>) -> Option<HttpResponse> {
+    if let Some(protocols) = tunnel::unsupported_upgrade(req) {
+        return Some(HttpResponse::NotImplemented().body(format!("upgrade to {} is not supported", protocols)));
+    }
+    if let Some(expect) = req.headers().get(header::EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Some(HttpResponse::ExpectationFailed().finish());
+        }
+    }
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match (declared, max_body) {
+        (Some(declared), Some(max_body)) if declared > max_body => Some(payload_too_large(max_body)),
+        _ => None,
+    }
+}
+
+/// `413 payload too large`. the connection is closed so the unread body
+/// does not have to be drained first.
+fn payload_too_large(max_body: u64) -> HttpResponse {
+    HttpResponse::PayloadTooLarge()
+        .force_close()
+        .body(format!("request body exceeds {} bytes", max_body))
+}
+
+/// the client's request headers as sent to the origin: without hop-by-hop
+/// headers and `Expect`, with the forwarding headers describing the client
+fn origin_request_headers(req: &HttpRequest, forwarding: &Forwarding) -> HeaderMap {
+    let mut headers = request_header_map(req);
+    // the client's expectation was met by actix already and the body is
+    // streamed to the origin as it arrives, so there is nothing to wait for
+    headers.remove(header::EXPECT);
+    // hop-by-hop headers go first, so `Connection` cannot name the
+    // forwarding headers added below
+    forwarding::strip_hop_by_hop(&mut headers);
+    // scheme and host as this hop saw them; connection_info() would take
+    // them from the very headers whose trust is being decided here
+    let proto = if Connection::is_secure(req) { "https" } else { "http" };
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    forwarding.apply(&mut headers, peer, proto, &request_host(req));
+    headers
+}
+
+/// the request to the origin for a client request, with the given body
+fn origin_request(
     actix_req: &HttpRequest,
-    mut payload: Payload, // actix_web::dev::Payload
+    forwarding: &Forwarding,
+    body: hyper::Body,
 ) -> Result<http::Request<hyper::Body>> {
     let mut hyper_req_builder = http::Request::builder()
         .method(actix_req.method().clone())
@@ -48,66 +288,823 @@ async fn actix_to_hyper_request(
             }
         });
 
-    for (name, value) in actix_req.headers() {
-        hyper_req_builder = hyper_req_builder.header(name, value.clone());
+    if let Some(headers) = hyper_req_builder.headers_mut() {
+        *headers = origin_request_headers(actix_req, forwarding);
     }
 
-    // actix_web::dev::Payload needs to be mapped to a stream of bytes for hyper::Body
-    let body_stream = futures_util::stream::poll_fn(move |cx| payload.poll_next(cx));
-    let hyper_body = hyper::Body::wrap_stream(body_stream);
+    let mut hyper_req = hyper_req_builder.body(body).map_err(ShadowError::Http)?;
+    // origins speaking the proxy protocol are told about the client connection
+    if let (Some(peer), Some(connection)) = (actix_req.peer_addr(), Connection::of(actix_req)) {
+        hyper_req.extensions_mut().insert(ProxyAddresses {
+            source: peer,
+            destination: connection.local_addr,
+        });
+    }
+    Ok(hyper_req)
+}
 
-    hyper_req_builder.body(hyper_body).map_err(ShadowError::Http)
+/// converts the client request for the origin, streaming its body. once more
+/// than `max_body` bytes have been read the body is aborted and
+/// `body_too_large` is set, so the failed fetch can be answered with 413.
+async fn actix_to_hyper_request(
+    actix_req: &HttpRequest,
+    mut payload: web::Payload,
+    forwarding: &Forwarding,
+    max_body: Option<u64>,
+    body_too_large: Arc<AtomicBool>,
+) -> Result<http::Request<hyper::Body>> {
+    // the actix payload is not Send, so it cannot back a hyper::Body directly.
+    // instead it is pumped into a channel from a task on the current worker thread.
+    let hyper_body = if has_request_body(actix_req) {
+        let (mut sender, body) = hyper::Body::channel();
+        actix_web::rt::spawn(async move {
+            let mut received = 0u64;
+            while let Some(chunk) = payload.next().await {
+                match chunk {
+                    Ok(bytes) => {
+                        received += bytes.len() as u64;
+                        if let Some(max_body) = max_body.filter(|max_body| received > *max_body) {
+                            warn!("request body exceeds {} bytes, aborting", max_body);
+                            body_too_large.store(true, Ordering::Relaxed);
+                            sender.abort();
+                            return;
+                        }
+                        if sender.send_data(bytes).await.is_err() {
+                            // origin side has gone away, nothing left to feed
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("failed to read request body: {}", e);
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        });
+        body
+    } else {
+        hyper::Body::empty()
+    };
+    origin_request(actix_req, forwarding, hyper_body)
 }
 
-fn hyper_to_actix_response(hyper_resp: http::Response<Bytes>) -> Result<HttpResponse> {
+fn origin_response_builder<B>(hyper_resp: &http::Response<B>) -> actix_web::HttpResponseBuilder {
     let mut actix_resp_builder = HttpResponse::build(hyper_resp.status());
     for (name, value) in hyper_resp.headers() {
         // care should be taken with headers that actix might set automatically
-        // or handle differently. for now, all headers are copied, but this
-        // may require refinement
-        actix_resp_builder.insert_header((name.clone(), value.clone()));
+        // or handle differently. hop-by-hop headers are dropped, everything else
+        // is copied as-is except for the origin's purge tags
+        if forwarding::is_hop_by_hop(name) || cache::is_tag_header(name) {
+            continue;
+        }
+        actix_resp_builder.append_header((name.clone(), value.clone()));
+    }
+    actix_resp_builder
+}
+
+fn hyper_to_actix_response(hyper_resp: &http::Response<Bytes>) -> Result<HttpResponse> {
+    Ok(origin_response_builder(hyper_resp).body(hyper_resp.body().clone()))
+}
+
+/// client response streaming an origin body. the length is taken from the
+/// origin's `content-length`, which actix then sets itself.
+fn streaming_response<B, S>(
+    head: &http::Response<B>,
+    length: Option<u64>,
+    stream: S,
+) -> HttpResponse
+where
+    S: futures_util::Stream<Item = std::result::Result<Bytes, hyper::Error>> + 'static,
+{
+    let mut builder = origin_response_builder(head);
+    let mut response = match length {
+        Some(length) => builder.body(SizedStream::new(length, stream)),
+        None => builder.streaming(stream),
+    };
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    response
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// resolves a requested asset name below the asset root, refusing anything
+/// that would escape it
+fn resolve_asset_path(root: &Path, filename: &str) -> Option<PathBuf> {
+    let relative = Path::new(filename);
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+#[get("/assets/{filename:.*}")]
+async fn serve_asset(
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let filename = path.into_inner();
+    let cache_key = req.path().to_string();
+
+    let cached = match app_state.cache.get(&cache_key, &HeaderMap::new()).await {
+        Some(cached) if cached.is_fresh() => Some((cached, "HIT")),
+        _ => {
+            let Some(file_path) = resolve_asset_path(&app_state.asset_path, &filename) else {
+                warn!("rejected asset path: {}", filename);
+                return HttpResponse::NotFound().body("not found");
+            };
+            debug!("looking for asset at: {:?}", file_path);
+
+            match tokio::fs::read(&file_path).await {
+                Ok(content) => {
+                    // generate an etag using a sha256 hash of the content.
+                    let digest = hex::encode(Sha256::digest(&content));
+                    let etag = format!("\"{}\"", &digest[..32]);
+                    let content_type = mime_guess::from_path(&filename).first_or_octet_stream();
+
+                    let mut headers = HeaderMap::new();
+                    headers.insert(header::ETAG, HeaderValue::from_str(&etag).expect("hex etag is a valid header"));
+                    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=86400"));
+                    if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+                        headers.insert(header::CONTENT_TYPE, value);
+                    }
+
+                    let cached = Arc::new(CachedResponse::new(
+                        StatusCode::OK,
+                        headers,
+                        Bytes::from(content),
+                        app_state.cache.default_ttl(),
+                        StaleWindows::default(),
+                    ));
+                    app_state
+                        .cache
+                        .insert_response(cache_key, &HeaderMap::new(), cached.clone())
+                        .await;
+
+                    info!("cache miss for asset: {}", filename);
+                    Some((cached, "MISS"))
+                }
+                Err(e) => {
+                    warn!("asset not found: {} - error: {}", filename, e);
+                    None
+                }
+            }
+        }
+    };
+
+    let Some((cached, cache_status)) = cached else {
+        return HttpResponse::NotFound().body("not found");
+    };
+
+    // if the client sent an `if-none-match` header matching our etag, skip the body.
+    let etag = cached.headers.get(header::ETAG);
+    if let (Some(etag), Some(if_none_match)) = (etag, req.headers().get(header::IF_NONE_MATCH)) {
+        if etag == if_none_match {
+            return HttpResponse::NotModified()
+                .insert_header((header::ETAG, etag.clone()))
+                .finish();
+        }
+    }
+
+    cached_to_actix_response(&cached, cache_status, &request_header_map(&req))
+}
+
+/// health of every origin and its servers, and whether all origins can take requests
+fn origins_health(router: &Router) -> (bool, serde_json::Map<String, serde_json::Value>) {
+    let mut all_available = true;
+    let origins = router
+        .origins()
+        .map(|origin| {
+            let pool = origin.fetcher.pool();
+            all_available &= pool.is_available();
+            let servers: Vec<_> = pool
+                .upstreams()
+                .iter()
+                .map(|upstream| {
+                    serde_json::json!({
+                        "url": upstream.url.as_str(),
+                        "healthy": upstream.probe_healthy(),
+                        "ejected": upstream.is_ejected(),
+                        "in_flight": upstream.in_flight(),
+                        "consecutive_errors": upstream.consecutive_errors(),
+                    })
+                })
+                .collect();
+            let mut health = serde_json::json!({
+                "available": pool.is_available(),
+                "servers": servers,
+            });
+            if let Some(breaker) = origin.fetcher.breaker() {
+                health["circuit_breaker"] = serde_json::json!({
+                    "state": breaker.state().label(),
+                    "opened": breaker.opened(),
+                    "rejected": breaker.rejected(),
+                });
+            }
+            (origin.name.clone(), health)
+        })
+        .collect();
+    (all_available, origins)
+}
+
+/// always answers 200 so that an origin outage does not get shadowstep
+/// itself restarted; `status` turns `degraded` while an origin has no
+/// server taking requests
+#[get("/health")]
+async fn health_check(app_state: web::Data<AppState>) -> impl Responder {
+    let stats = app_state.cache.stats().await;
+    let (all_available, origins) = origins_health(&app_state.router);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": if all_available { "ok" } else { "degraded" },
+        "origins": origins,
+        "cache": {
+            "hits": stats.hits,
+            "misses": stats.misses,
+            "revalidations": stats.revalidations,
+            "stale": stats.stale,
+            "items": stats.items,
+            "bytes_used": stats.bytes_used,
+            "capacity_bytes": stats.capacity_bytes,
+            "disk_hits": stats.disk_hits,
+            "disk_items": stats.disk_items,
+            "disk_bytes_used": stats.disk_bytes_used,
+            "hit_ratio": stats.hit_ratio(),
+            "by_status": StatusClass::ALL
+                .iter()
+                .map(|&class| {
+                    let counts = serde_json::json!({
+                        "hits": stats.hits_for(class),
+                        "misses": stats.misses_for(class),
+                    });
+                    (class.label().to_string(), counts)
+                })
+                .collect::<serde_json::Map<_, _>>(),
+        },
+        "coalescing": {
+            "enabled": app_state.coalescer.is_some(),
+            "collapsed": app_state.coalescer.as_ref().map_or(0, |c| c.collapsed()),
+        },
+        "tunnels": {
+            "active": app_state.tunnels.active(),
+            "opened": app_state.tunnels.opened(),
+            "bytes_to_origin": app_state.tunnels.bytes_to_origin(),
+            "bytes_to_client": app_state.tunnels.bytes_to_client(),
+        }
+    }))
+}
+
+/// multipart boundaries only need to be unlikely to occur in the body
+fn multipart_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    format!("shadowstep-{:08x}{:08x}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// answers a range request from a complete `200` response, turning it into
+/// a `206` (single or `multipart/byteranges`) or a `416`. other responses,
+/// and requests without a usable range, are returned unchanged.
+fn apply_range(mut response: HttpResponse, request_headers: &HeaderMap, body: &Bytes) -> HttpResponse {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let headers = response.headers();
+    let outcome = range::evaluate(
+        request_headers,
+        headers.get(header::ETAG),
+        headers.get(header::LAST_MODIFIED),
+        body.len() as u64,
+    );
+    let total = body.len() as u64;
+    let partial_body = match outcome {
+        RangeOutcome::Full => return response,
+        RangeOutcome::NotSatisfiable => {
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes */{}", total)).expect("valid content-range"));
+            headers.remove(header::CONTENT_TYPE);
+            Bytes::new()
+        }
+        RangeOutcome::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            let content_range = HeaderValue::from_str(&range.content_range(total)).expect("valid content-range");
+            response.headers_mut().insert(header::CONTENT_RANGE, content_range);
+            body.slice(range.start as usize..=range.end as usize)
+        }
+        RangeOutcome::Partial(ranges) => {
+            let boundary = multipart_boundary();
+            let multipart = range::multipart_body(&ranges, body, response.headers().get(header::CONTENT_TYPE), &boundary);
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            let content_type = HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                .expect("valid multipart content-type");
+            response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+            multipart
+        }
+    };
+
+    // byte offsets refer to the unencoded body, so it must not be compressed on the way out
+    if !response.headers().contains_key(header::CONTENT_ENCODING) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+    }
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    response.set_body(partial_body).map_into_boxed_body()
+}
+
+fn with_cache_status(mut response: HttpResponse, cache_status: &'static str) -> HttpResponse {
+    response.headers_mut().insert(
+        HeaderName::from_static("x-shadowstep-cache"),
+        HeaderValue::from_static(cache_status),
+    );
+    response
+}
+
+/// builds a client response from a cache entry, tagged with how it was served.
+/// range requests for complete entries are answered from the stored body.
+fn cached_to_actix_response(
+    cached: &CachedResponse,
+    cache_status: &'static str,
+    request_headers: &HeaderMap,
+) -> HttpResponse {
+    // build response from cached data, preserving status and headers
+    let mut response_builder = HttpResponse::build(cached.status);
+
+    // copy the original headers from the cached response, keeping purge tags internal
+    for (name, value) in cached.headers.iter().filter(|(name, _)| !cache::is_tag_header(name)) {
+        response_builder.append_header((name, value));
+    }
+
+    // add cache status indicator header and the entry's current age
+    response_builder.insert_header(("X-Shadowstep-Cache", cache_status));
+    response_builder.insert_header((header::AGE, cached.age().as_secs()));
+    if cached.status == StatusCode::OK {
+        response_builder.insert_header((header::ACCEPT_RANGES, "bytes"));
+    }
+
+    let response = response_builder.body(cached.body.clone());
+    apply_range(response, request_headers, &cached.body)
+}
+
+/// turns a request to the origin into a conditional one, validating the
+/// stale entry instead of whatever the client may have sent
+fn add_conditional_headers(headers: &mut HeaderMap, stale: &CachedResponse) {
+    headers.remove(header::IF_NONE_MATCH);
+    headers.remove(header::IF_MODIFIED_SINCE);
+    if let Some(etag) = stale.headers.get(header::ETAG) {
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+    }
+    if let Some(last_modified) = stale.headers.get(header::LAST_MODIFIED) {
+        headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+    }
+}
+
+/// how long a response to the given request may be cached, if at all. the
+/// origin's explicit freshness wins, otherwise the ttl configured for the status.
+fn response_ttl(
+    app_state: &AppState,
+    origin: &Origin,
+    request_headers: &HeaderMap,
+    status: StatusCode,
+    response_headers: &HeaderMap,
+) -> Option<Duration> {
+    // explicit freshness from the origin overrides the configured defaults,
+    // even where those disable caching; only the status and the origin's
+    // `cache` switch can rule a response out entirely
+    if !origin.cache || !cache::caches_status(status) {
+        return None;
     }
-    Ok(actix_resp_builder.body(hyper_resp.into_body()))
+    freshness::storable_ttl(
+        &CacheControl::from_headers(request_headers),
+        request_headers.contains_key(header::AUTHORIZATION),
+        response_headers,
+        origin.default_ttl_for(status, app_state.cache.default_ttl_for(status)),
+        SystemTime::now(),
+    )
+}
+
+/// applies a `304 not modified` from the origin to a stale entry: its
+/// headers and ttl are refreshed and the stored body is kept
+async fn refresh_not_modified(
+    app_state: &AppState,
+    origin: &Origin,
+    cache_key: &str,
+    request_headers: &HeaderMap,
+    stale: &CachedResponse,
+    not_modified: &HeaderMap,
+) -> Arc<CachedResponse> {
+    let refreshed_headers = stale.headers_refreshed_by(not_modified);
+    let ttl = response_ttl(app_state, origin, request_headers, stale.status, &refreshed_headers);
+    let stale_windows = app_state.cache.stale_windows(&refreshed_headers);
+    let refreshed = Arc::new(CachedResponse::new(
+        stale.status,
+        refreshed_headers,
+        stale.body.clone(),
+        ttl.unwrap_or_default(),
+        stale_windows,
+    ));
+    app_state.cache.record_revalidation();
+    if ttl.is_some() {
+        debug!("revalidated cached response for: {}", cache_key);
+        app_state
+            .cache
+            .insert_response(cache_key.to_string(), request_headers, refreshed.clone())
+            .await;
+    } else {
+        debug!("revalidated response no longer storable for: {}", cache_key);
+        app_state.cache.invalidate(cache_key).await;
+    }
+    refreshed
+}
+
+/// caches an origin response to a GET for as long as the origin allows.
+/// redirects, 404/410 and origin errors are negatively cached when enabled.
+async fn store_response(
+    app_state: &AppState,
+    origin: &Origin,
+    cache_key: &str,
+    request_headers: &HeaderMap,
+    origin_response: &http::Response<Bytes>,
+) {
+    let status = origin_response.status();
+    match response_ttl(app_state, origin, request_headers, status, origin_response.headers()) {
+        Some(_) if status.is_success() && origin_response.body().is_empty() => {
+            debug!("not caching empty response for: {}", cache_key);
+        }
+        Some(ttl) => {
+            debug!("caching {} response for: {} (ttl {}s)", status, cache_key, ttl.as_secs());
+            app_state
+                .cache
+                .insert(
+                    cache_key.to_string(),
+                    request_headers,
+                    status,
+                    origin_response.headers().clone(),
+                    origin_response.body().clone(),
+                    ttl,
+                )
+                .await;
+        }
+        None => {
+            debug!("response not storable for: {}", cache_key);
+        }
+    }
+}
+
+/// refreshes a stale entry off the request path (stale-while-revalidate).
+/// at most one refresh per cache key is in flight at a time. `origin_headers`
+/// are sent to the origin, the client's `request_headers` select the variant.
+fn spawn_background_refresh(
+    app_state: web::Data<AppState>,
+    origin: Arc<Origin>,
+    cache_key: String,
+    uri: http::Uri,
+    origin_headers: HeaderMap,
+    request_headers: HeaderMap,
+    stale: Arc<CachedResponse>,
+) {
+    if !app_state.refreshing.lock().unwrap().insert(cache_key.clone()) {
+        debug!("background refresh already running for: {}", cache_key);
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut origin_request = http::Request::new(hyper::Body::empty());
+        *origin_request.uri_mut() = uri;
+        *origin_request.headers_mut() = origin_headers;
+        // the refresh replaces the whole entry, not the range this client asked for
+        origin_request.headers_mut().remove(header::RANGE);
+        origin_request.headers_mut().remove(header::IF_RANGE);
+        add_conditional_headers(origin_request.headers_mut(), &stale);
+
+        match origin.fetcher.fetch_from_origin(origin_request, &cache_key).await {
+            Ok(origin_response) if origin_response.status() == StatusCode::NOT_MODIFIED => {
+                refresh_not_modified(
+                    &app_state,
+                    &origin,
+                    &cache_key,
+                    &request_headers,
+                    &stale,
+                    origin_response.headers(),
+                )
+                .await;
+            }
+            // an origin error must not replace the stale entry
+            Ok(origin_response) if !origin_response.status().is_server_error() => {
+                let (parts, body) = origin_response.into_parts();
+                let limit = app_state.cache.max_object_size() as usize;
+                match body::read_limited(body, limit).await {
+                    Ok(Limited::Complete(body)) => {
+                        let origin_response = http::Response::from_parts(parts, body);
+                        store_response(&app_state, &origin, &cache_key, &request_headers, &origin_response).await;
+                    }
+                    Ok(Limited::Overflow(..)) => {
+                        debug!("background refresh for {} exceeds the max object size", cache_key);
+                        app_state.cache.invalidate(&cache_key).await;
+                    }
+                    Err(e) => warn!("background refresh for {} failed: {}", cache_key, e),
+                }
+            }
+            Ok(origin_response) => {
+                warn!(
+                    "background refresh for {} returned {}",
+                    cache_key,
+                    origin_response.status()
+                );
+            }
+            Err(e) => warn!("background refresh for {} failed: {}", cache_key, e),
+        }
+
+        app_state.refreshing.lock().unwrap().remove(&cache_key);
+    });
+}
+
+/// fetches from the origin, sharing the fetch with concurrent requests for the
+/// same key. followers get the leader's response once its body is complete;
+/// the leader streams it and completes the fetch when done.
+async fn coalesced_fetch(
+    origin: &Origin,
+    coalescer: &Arc<Coalescer<SharedFetch>>,
+    key: &str,
+    request_headers: &HeaderMap,
+    has_stale: bool,
+    hyper_request: http::Request<hyper::Body>,
+) -> std::result::Result<Fetched, FetchError> {
+    match coalescer.join(key) {
+        Role::Leader(leader) => match fetch_origin(origin, cache::primary_key(key), hyper_request).await {
+            Ok(response) => Ok(Fetched {
+                response: response.map(OriginBody::Streaming),
+                leader: Some(leader),
+            }),
+            Err(e) => {
+                leader.complete(SharedFetch {
+                    result: Err(e.clone()),
+                    request_headers: Arc::new(request_headers.clone()),
+                });
+                Err(e)
+            }
+        },
+        Role::Follower(receiver) => {
+            if let Some(shared) = coalescer.wait(receiver).await {
+                if shared.usable_for(request_headers, has_stale) {
+                    debug!("collapsed request onto in-flight fetch for: {}", key);
+                    coalescer.record_collapsed();
+                    return shared.result.map(|response| {
+                        let body = OriginBody::Buffered(response.body().clone());
+                        Fetched {
+                            response: response_with_body(&response, body),
+                            leader: None,
+                        }
+                    });
+                }
+            }
+            debug!("in-flight fetch not usable for: {}, fetching directly", key);
+            fetch_uncoalesced(origin, cache::primary_key(key), hyper_request).await
+        }
+    }
+}
+
+async fn fetch_origin(
+    origin: &Origin,
+    cache_key: &str,
+    hyper_request: http::Request<hyper::Body>,
+) -> std::result::Result<http::Response<hyper::Body>, FetchError> {
+    origin
+        .fetcher
+        .fetch_from_origin(hyper_request, cache_key)
+        .await
+        .map_err(FetchError::from)
+}
+
+async fn fetch_uncoalesced(
+    origin: &Origin,
+    cache_key: &str,
+    hyper_request: http::Request<hyper::Body>,
+) -> std::result::Result<Fetched, FetchError> {
+    let response = fetch_origin(origin, cache_key, hyper_request).await?;
+    Ok(Fetched {
+        response: response.map(OriginBody::Streaming),
+        leader: None,
+    })
+}
+
+/// host the client addressed, from the request target (http/2) or `host` header
+fn request_host(req: &HttpRequest) -> String {
+    req.uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| req.headers().get(header::HOST).and_then(|v| v.to_str().ok()))
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// cache key of a proxied request, composed by the configured key policy
+fn request_cache_key(policy: &CacheKeyPolicy, req: &HttpRequest, request_headers: &HeaderMap) -> String {
+    // scheme and host as the router sees them. forwarding headers, even a
+    // trusted proxy's, may carry what a client sent and must not file one
+    // origin's response under another host's key
+    let scheme = if Connection::is_secure(req) { "https" } else { "http" };
+    let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    policy.key(scheme, &request_host(req), path_and_query, request_headers)
+}
+
+/// answer to a failed origin fetch, when there is nothing stale to fall back on
+fn fetch_error_response(req: &HttpRequest, e: &FetchError, start_time: Instant) -> HttpResponse {
+    // an open circuit is a deliberate refusal, not a failed fetch
+    if let Some(retry_after) = e.retry_after {
+        info!(
+            "{} {} -> {} {}ms (circuit open)",
+            req.method(),
+            req.uri(),
+            "503 Service Unavailable",
+            start_time.elapsed().as_millis()
+        );
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1).to_string()))
+            .body(format!("origin unavailable: {}", e));
+    }
+
+    if e.timed_out {
+        info!(
+            "{} {} -> {} {}ms (timeout)",
+            req.method(),
+            req.uri(),
+            "504 Gateway Timeout",
+            start_time.elapsed().as_millis()
+        );
+        return HttpResponse::GatewayTimeout().body(format!("origin fetch error: {}", e));
+    }
+
+    info!(
+        "{} {} -> {} {}ms (error)",
+        req.method(),
+        req.uri(),
+        "502 Bad Gateway",
+        start_time.elapsed().as_millis()
+    );
+    HttpResponse::BadGateway().body(format!("origin fetch error: {}", e))
+}
+
+/// forwards a websocket handshake and, once the origin switches protocols,
+/// splices the client connection with the origin's
+async fn open_tunnel(
+    req: &HttpRequest,
+    payload: web::Payload,
+    app_state: &AppState,
+    origin: &Origin,
+    cache_key: &str,
+    protocol: HeaderValue,
+    start_time: Instant,
+) -> HttpResponse {
+    // the client sends nothing but the handshake until it is answered
+    let mut hyper_request = match origin_request(req, &app_state.forwarding, hyper::Body::empty()) {
+        Ok(h_req) => h_req,
+        Err(e) => {
+            error!("failed to convert request: {}", e);
+            return HttpResponse::InternalServerError()
+                .body(format!("request conversion error: {}", e));
+        }
+    };
+    hyper_request.extensions_mut().insert(UpgradeProtocol(protocol.clone()));
+    let mut response = match fetch_origin(origin, cache_key, hyper_request).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("failed to open tunnel to origin: {}", e);
+            return fetch_error_response(req, &e, start_time);
+        }
+    };
+
+    // the origin declined the upgrade, its answer is passed on as it is
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        info!(
+            "{} {} -> {} {}ms (upgrade declined)",
+            req.method(),
+            req.uri(),
+            response.status(),
+            start_time.elapsed().as_millis()
+        );
+        let (parts, body) = response.into_parts();
+        let head = http::Response::from_parts(parts, ());
+        let mut response = streaming_response(&head, content_length(head.headers()), body);
+        // actix has stopped parsing requests on this connection
+        response.head_mut().set_connection_type(actix_web::http::ConnectionType::Close);
+        return response;
+    }
+    let upgraded = match hyper::upgrade::on(&mut response).await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            error!("origin switched protocols, but its connection could not be taken over: {}", e);
+            return HttpResponse::BadGateway().body(format!("origin upgrade error: {}", e));
+        }
+    };
+    info!(
+        "{} {} -> {} {}ms (tunnel)",
+        req.method(),
+        req.uri(),
+        response.status(),
+        start_time.elapsed().as_millis()
+    );
+    let mut builder = origin_response_builder(&response);
+    let client = app_state
+        .forwarding
+        .request_client_ip(req)
+        .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    let label = format!("{} {} for {}", origin.name, req.uri(), client);
+    let body = tunnel::splice(
+        upgraded,
+        payload,
+        app_state.tunnel_idle_timeout,
+        app_state.tunnels.clone(),
+        label,
+        response.into_body(),
+    );
+    builder.upgrade(protocol).streaming(body)
 }
 
 async fn forward_request(
     req: HttpRequest,
-    payload: Payload, // actix_web::dev::Payload, not web::Payload
+    payload: web::Payload,
     app_state: web::Data<AppState>,
 ) -> impl Responder {
     let start_time = Instant::now();
-    let cache_key = req.uri().to_string();
-
-    // currently, only get requests are considered for caching
-    if req.method() == actix_web::http::Method::GET {
-        if let Some(cached_response) = app_state.cache.get(&cache_key).await {
-            debug!("cache hit for: {}", cache_key);
-            
-            // build response from cached data, preserving status and headers
-            let mut response_builder = HttpResponse::build(cached_response.status);
-            
-            // copy all original headers from the cached response
-            for (name, value) in cached_response.headers.iter() {
-                response_builder.insert_header((name, value));
-            }
-            
-            // add cache hit indicator header
-            response_builder.insert_header(("X-Shadowstep-Cache", "HIT"));
-            
-            info!(
-                "{} {} -> {} {}ms (cached)",
-                req.method(),
-                req.uri(),
-                cached_response.status,
-                start_time.elapsed().as_millis()
-            );
-            
-            return response_builder.body(cached_response.body.clone());
+    if let Some(response) = check_request_head(&req, app_state.max_request_body) {
+        info!("{} {} -> {} (refused)", req.method(), req.uri(), response.status());
+        return response;
+    }
+    let request_headers = request_header_map(&req);
+    let cache_key = request_cache_key(app_state.cache.key_policy(), &req, &request_headers);
+    let origin = app_state
+        .router
+        .route(req.method(), &request_host(&req), req.uri().path())
+        .clone();
+    debug!("routing {} {} to origin {}", req.method(), req.uri(), origin.name);
+
+    // websocket handshakes are never cached; the connection becomes a tunnel
+    if let Some(protocol) = tunnel::requested_upgrade(&req) {
+        return open_tunnel(&req, payload, &app_state, &origin, &cache_key, protocol, start_time).await;
+    }
+
+    // currently, only get requests are considered for caching. stale entries
+    // are served while being refreshed when their stale-while-revalidate
+    // window allows it, and otherwise revalidated with the origin.
+    let mut stale_response = None;
+    if req.method() == actix_web::http::Method::GET && origin.cache {
+        match app_state.cache.get(&cache_key, &request_headers).await {
+            Some(cached_response) if cached_response.is_fresh() => {
+                debug!("cache hit for: {}", cache_key);
+                info!(
+                    "{} {} -> {} {}ms (cached)",
+                    req.method(),
+                    req.uri(),
+                    cached_response.status,
+                    start_time.elapsed().as_millis()
+                );
+                return cached_to_actix_response(&cached_response, "HIT", &request_headers);
+            }
+            Some(cached_response) if cached_response.can_serve_while_revalidating() => {
+                debug!("serving stale entry while revalidating: {}", cache_key);
+                app_state.cache.record_stale();
+                spawn_background_refresh(
+                    app_state.clone(),
+                    origin.clone(),
+                    cache_key.clone(),
+                    req.uri().clone(),
+                    origin_request_headers(&req, &app_state.forwarding),
+                    request_headers.clone(),
+                    cached_response.clone(),
+                );
+                info!(
+                    "{} {} -> {} {}ms (stale)",
+                    req.method(),
+                    req.uri(),
+                    cached_response.status,
+                    start_time.elapsed().as_millis()
+                );
+                return cached_to_actix_response(&cached_response, "STALE", &request_headers);
+            }
+            Some(cached_response) => {
+                debug!("revalidating stale entry for: {}", cache_key);
+                stale_response = Some(cached_response);
+            }
+            None => debug!("cache miss for: {}", cache_key),
         }
-        debug!("cache miss for: {}", cache_key);
     }
 
-    let hyper_request = match actix_to_hyper_request(&req, payload).await {
+    let body_too_large = Arc::new(AtomicBool::new(false));
+    let hyper_request =
+        actix_to_hyper_request(&req, payload, &app_state.forwarding, app_state.max_request_body, body_too_large.clone());
+    let mut hyper_request = match hyper_request.await {
         Ok(h_req) => h_req,
         Err(e) => {
             error!("failed to convert request: {}", e);
@@ -115,39 +1112,234 @@ async fn forward_request(
                 .body(format!("request conversion error: {}", e));
         }
     };
+    if let Some(stale) = &stale_response {
+        add_conditional_headers(hyper_request.headers_mut(), stale);
+    }
 
-    match app_state.fetcher.fetch_from_origin(hyper_request).await {
-        Ok(origin_response) => {
-            let status = origin_response.status();
-            let headers = origin_response.headers().clone(); // clone headers for caching
-            let response_bytes = origin_response.body().clone(); // clone bytes for potential caching
+    // range misses either fetch the whole object, which is cached and the range
+    // served from it, or go to the origin as they are and are not cached
+    let is_range = req.method() == actix_web::http::Method::GET && request_headers.contains_key(header::RANGE);
+    if is_range && app_state.range_fetch_full {
+        hyper_request.headers_mut().remove(header::RANGE);
+        hyper_request.headers_mut().remove(header::IF_RANGE);
+    }
 
-            // cache successful get responses
-            if req.method() == actix_web::http::Method::GET && status.is_success() {
-                if !response_bytes.is_empty() {
-                    debug!("caching response for: {}", cache_key);
-                    app_state
-                        .cache
-                        .insert(cache_key.clone(), status, headers.clone(), response_bytes.clone())
+    // only anonymous gets to caching origins are coalesced, anything carrying
+    // credentials may be personalised, and passed-through ranges are specific
+    // to one request
+    let coalescer = app_state.coalescer.as_ref().filter(|_| {
+        origin.cache
+            && req.method() == actix_web::http::Method::GET
+            && !request_headers.contains_key(header::AUTHORIZATION)
+            && !request_headers.contains_key(header::COOKIE)
+            && (!is_range || app_state.range_fetch_full)
+    });
+    let origin_result = match coalescer {
+        Some(coalescer) => {
+            let key = app_state.cache.lookup_key(&cache_key, &request_headers).await;
+            coalesced_fetch(
+                &origin,
+                coalescer,
+                &key,
+                &request_headers,
+                stale_response.is_some(),
+                hyper_request,
+            )
+            .await
+        }
+        None => fetch_uncoalesced(&origin, &cache_key, hyper_request).await,
+    };
+
+    match origin_result {
+        Ok(Fetched { response, leader }) => {
+            let status = response.status();
+
+            if let Some(stale) = &stale_response {
+                let not_modified = status == StatusCode::NOT_MODIFIED;
+                let serve_stale = status.is_server_error() && stale.can_serve_on_error();
+                if not_modified || serve_stale {
+                    // these bodies are small and never sent on; buffer them so
+                    // coalesced followers get the same answer
+                    let (parts, body) = response.into_parts();
+                    let body = discard_body(body).await;
+                    let origin_response = http::Response::from_parts(parts, body);
+
+                    // the origin confirmed the stale entry: refresh its headers and ttl, keep the body
+                    if not_modified {
+                        let refreshed = refresh_not_modified(
+                            &app_state,
+                            &origin,
+                            &cache_key,
+                            &request_headers,
+                            stale,
+                            origin_response.headers(),
+                        )
                         .await;
-                } else {
-                    debug!("not caching empty response for: {}", cache_key);
+                        share(leader, &request_headers, origin_response);
+                        info!(
+                            "{} {} -> {} {}ms (revalidated)",
+                            req.method(),
+                            req.uri(),
+                            refreshed.status,
+                            start_time.elapsed().as_millis()
+                        );
+                        return cached_to_actix_response(&refreshed, "REVALIDATED", &request_headers);
+                    }
+
+                    // the origin is failing: fall back to the stale entry (stale-if-error)
+                    share(leader, &request_headers, origin_response);
+                    warn!("origin returned {} for {}, serving stale", status, cache_key);
+                    app_state.cache.record_stale();
+                    info!(
+                        "{} {} -> {} {}ms (stale)",
+                        req.method(),
+                        req.uri(),
+                        stale.status,
+                        start_time.elapsed().as_millis()
+                    );
+                    return cached_to_actix_response(stale, "STALE", &request_headers);
                 }
             }
 
-            let mut actix_http_response = match hyper_to_actix_response(origin_response) {
-                Ok(r) => r,
-                Err(e) => {
-                    error!("failed to convert origin response: {}", e);
-                    return HttpResponse::InternalServerError()
-                        .body(format!("response conversion error: {}", e));
+            let is_get = req.method() == actix_web::http::Method::GET;
+            if is_get {
+                app_state.cache.record_origin_response(status);
+            }
+            let range_from_full = is_range && app_state.range_fetch_full;
+            let limit = app_state.cache.max_object_size() as usize;
+            // followers are handed the leader's complete body
+            let collapsed = matches!(response.body(), OriginBody::Buffered(_));
+            let (parts, body) = response.into_parts();
+            let head = http::Response::from_parts(parts, ());
+            let length = content_length(head.headers());
+
+            // responses without a body to stream are simply buffered
+            let has_body = req.method() != actix_web::http::Method::HEAD
+                && !matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+                && !status.is_informational();
+            let body = match body {
+                OriginBody::Streaming(body) if !has_body => {
+                    let bytes = hyper::body::to_bytes(body).await.unwrap_or_default();
+                    OriginBody::Buffered(bytes)
+                }
+                // a range miss fetched in full: read the object so it can be
+                // cached and the range cut from it, if it is small enough
+                OriginBody::Streaming(body)
+                    if range_from_full && length.is_none_or(|l| l <= limit as u64) =>
+                {
+                    match body::read_limited(body, limit).await {
+                        Ok(Limited::Complete(bytes)) => OriginBody::Buffered(bytes),
+                        Ok(Limited::Overflow(prefix, rest)) => {
+                            // unknown length and too big to cache: send it all
+                            info!(
+                                "{} {} -> {} {}ms",
+                                req.method(),
+                                req.uri(),
+                                status,
+                                start_time.elapsed().as_millis()
+                            );
+                            let stream = body::chain(prefix, rest);
+                            return with_cache_status(streaming_response(&head, None, stream), "MISS");
+                        }
+                        Err(e) => {
+                            warn!("failed to read origin response for {}: {}", cache_key, e);
+                            return HttpResponse::BadGateway()
+                                .body(format!("origin fetch error: {}", e));
+                        }
+                    }
                 }
+                body => body,
             };
 
-            actix_http_response.headers_mut().insert(
-                HeaderName::from_static("x-shadowstep-cache"),
-                HeaderValue::from_static("MISS"),
-            );
+            let actix_http_response = match body {
+                OriginBody::Buffered(bytes) => {
+                    let origin_response = head.map(|()| bytes);
+                    // a collapsed response was already stored and shared by the
+                    // request that fetched it
+                    if let Some(leader) = leader {
+                        if is_get {
+                            store_response(&app_state, &origin, &cache_key, &request_headers, &origin_response).await;
+                        }
+                        let shared = response_with_body(&origin_response, origin_response.body().clone());
+                        share(Some(leader), &request_headers, shared);
+                    } else if is_get && !collapsed {
+                        store_response(&app_state, &origin, &cache_key, &request_headers, &origin_response).await;
+                    }
+                    let response = match hyper_to_actix_response(&origin_response) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("failed to convert origin response: {}", e);
+                            return HttpResponse::InternalServerError()
+                                .body(format!("response conversion error: {}", e));
+                        }
+                    };
+                    if range_from_full {
+                        apply_range(response, &request_headers, origin_response.body())
+                    } else {
+                        response
+                    }
+                }
+                OriginBody::Streaming(body) if range_from_full => {
+                    // too big to cache: stream just the requested range if it is a single one
+                    let ranges = length.map(|length| {
+                        range::evaluate(
+                            &request_headers,
+                            head.headers().get(header::ETAG),
+                            head.headers().get(header::LAST_MODIFIED),
+                            length,
+                        )
+                    });
+                    match (ranges, length) {
+                        (Some(RangeOutcome::Partial(ranges)), Some(length))
+                            if ranges.len() == 1 && status == StatusCode::OK =>
+                        {
+                            let range = ranges[0];
+                            let stream = body::slice(body, range.start, range.end);
+                            let mut response =
+                                streaming_response(&head, Some(range.end - range.start + 1), stream);
+                            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                            let content_range = HeaderValue::from_str(&range.content_range(length))
+                                .expect("valid content-range");
+                            let headers = response.headers_mut();
+                            headers.insert(header::CONTENT_RANGE, content_range);
+                            if !headers.contains_key(header::CONTENT_ENCODING) {
+                                headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+                            }
+                            response
+                        }
+                        _ => streaming_response(&head, length, body),
+                    }
+                }
+                OriginBody::Streaming(body) => {
+                    // tee into the cache when the response may be stored, or
+                    // when followers are waiting for it
+                    let storable = is_get
+                        && length.is_none_or(|l| l <= limit as u64)
+                        && response_ttl(&app_state, &origin, &request_headers, status, head.headers()).is_some();
+                    if storable || leader.is_some() {
+                        let (tee, done) = TeeBody::new(body, limit);
+                        let app_state = app_state.clone();
+                        let origin = origin.clone();
+                        let cache_key = cache_key.clone();
+                        let request_headers = request_headers.clone();
+                        let completed_head = response_with_body(&head, ());
+                        actix_web::rt::spawn(async move {
+                            let Ok(bytes) = done.await else {
+                                debug!("response for {} not completed, not caching it", cache_key);
+                                return;
+                            };
+                            let origin_response = completed_head.map(|()| bytes);
+                            if storable {
+                                store_response(&app_state, &origin, &cache_key, &request_headers, &origin_response).await;
+                            }
+                            share(leader, &request_headers, origin_response);
+                        });
+                        streaming_response(&head, length, tee)
+                    } else {
+                        streaming_response(&head, length, body)
+                    }
+                }
+            };
 
             info!(
                 "{} {} -> {} {}ms",
@@ -156,68 +1348,189 @@ async fn forward_request(
                 status,
                 start_time.elapsed().as_millis()
             );
-            actix_http_response
+            with_cache_status(actix_http_response, "MISS")
         }
         Err(e) => {
-            error!("failed to fetch from origin: {}", e);
-            info!(
-                "{} {} -> {} {}ms (error)",
-                req.method(),
-                req.uri(),
-                "502 Bad Gateway",
-                start_time.elapsed().as_millis()
-            );
-            HttpResponse::BadGateway().body(format!("origin fetch error: {}", e))
+            // the fetch was cut short because the client sent too much
+            if let (true, Some(max_body)) = (body_too_large.load(Ordering::Relaxed), app_state.max_request_body) {
+                info!("{} {} -> 413 {}ms (body too large)", req.method(), req.uri(), start_time.elapsed().as_millis());
+                return payload_too_large(max_body);
+            }
+            if e.retry_after.is_some() {
+                debug!("not fetching from origin: {}", e);
+            } else {
+                error!("failed to fetch from origin: {}", e);
+            }
+
+            // connection-level failures also fall back to a stale entry
+            if let (true, Some(stale)) = (e.transport, &stale_response) {
+                if stale.can_serve_on_error() {
+                    app_state.cache.record_stale();
+                    info!(
+                        "{} {} -> {} {}ms (stale)",
+                        req.method(),
+                        req.uri(),
+                        stale.status,
+                        start_time.elapsed().as_millis()
+                    );
+                    return cached_to_actix_response(stale, "STALE", &request_headers);
+                }
+            }
+
+            fetch_error_response(&req, &e, start_time)
         }
     }
 }
 
+/// builds and runs the cdn server until it is shut down.
+///
+/// this is the single entry point used by the binary; embedders construct a
+/// [`Config`] themselves and call this from within an actix system.
 pub async fn run(config: Config) -> Result<()> {
     let app_config = Arc::new(config.clone()); // arc for sharing config across httpserver threads
 
-    let fetcher = Arc::new(OriginFetcher::new(&app_config)?);
-    let cache = Arc::new(CdnCache::new(
-        app_config.cache_size_mb,
-        app_config.cache_ttl_seconds,
-    ));
+    std::fs::create_dir_all(&app_config.asset_path)?;
+
+    let router = Router::new(&app_config)?;
+    let cache = Arc::new(CdnCache::new(&app_config).await?);
+    // hosts routed to different origins would otherwise share cache entries
+    if router.routes_by_host() && !cache.key_policy().always_includes_host() {
+        return Err(ShadowError::Config(
+            "origins are routed by host, so every cache key rule needs `include_host`".into(),
+        ));
+    }
 
     // appstate is constructed once and cloned by actix for each worker thread
     // when passed as web::Data::new(...)
+    // the admin api shares the cache but runs on its own address
+    let admin_server = if app_config.is_admin_enabled() {
+        Some(admin::server(&app_config, cache.clone())?)
+    } else {
+        None
+    };
+
     let app_state_data = web::Data::new(AppState {
-        fetcher,
+        router,
         cache,
+        asset_path: app_config.asset_path.clone(),
+        refreshing: Mutex::new(HashSet::new()),
+        coalescer: (app_config.coalesce_timeout_ms > 0)
+            .then(|| {
+                Arc::new(Coalescer::new(Duration::from_millis(
+                    app_config.coalesce_timeout_ms,
+                )))
+            }),
+        range_fetch_full: app_config.range_fetch_full,
+        max_request_body: (app_config.max_request_body_kb > 0)
+            .then(|| app_config.max_request_body_kb * 1024),
+        forwarding: Forwarding::from_config(&app_config),
+        tunnel_idle_timeout: (app_config.tunnel_idle_timeout_seconds > 0)
+            .then(|| Duration::from_secs(app_config.tunnel_idle_timeout_seconds)),
+        tunnels: Arc::new(TunnelStats::default()),
         // config: app_config.clone(), // no longer storing full config directly in appstate
     });
 
-    info!("shadowstep server starting on {}", app_config.listen_addr);
-    if app_config.is_tls_enabled() {
-        info!("tls is enabled.");
-    } else {
-        info!("tls is disabled (http only).");
+    health::spawn_probes(&app_state_data.router);
+
+    let num_workers = num_cpus::get();
+    for origin in app_state_data.router.origins() {
+        let pool = origin.fetcher.pool();
+        let servers: Vec<&str> = pool.upstreams().iter().map(|u| u.url.as_str()).collect();
+        info!("proxying to origin {} ({:?}): {}", origin.name, pool.balance(), servers.join(", "));
     }
+    info!("serving assets from: {:?}", app_config.asset_path);
+    info!(
+        "shadowstep server starting on {} with {} workers",
+        app_config.listen_addr, num_workers
+    );
 
-    let server_builder = HttpServer::new(move || {
+    let forwarding = app_state_data.forwarding.clone();
+    let max_request_body = app_state_data.max_request_body;
+    let app = move || {
         App::new()
             .app_data(app_state_data.clone()) // clones the web::Data<AppState> for this worker
-            .wrap(Logger::default()) // default logger format: "%r" %s %b "%R" %Dms
+            .wrap(Compress::default())
+            .wrap(forwarding.access_logger())
+            .service(health_check)
+            .service(serve_asset)
             .default_service(web::to(forward_request))
-    });
+    };
+    let keep_alive = Duration::from_secs(75);
+    // handle graceful shutdown with signals
+    let mut server = actix_server::Server::build()
+        .workers(num_workers)
+        .shutdown_timeout(30); // 30 second graceful shutdown period
 
-    let server = if app_config.is_tls_enabled() {
-        let tls_rustls_config = load_rustls_config(&app_config)?.ok_or_else(|| {
-            ShadowError::TlsConfig("tls enabled but rustls config failed to load".to_string())
-        })?;
-        server_builder
-            .bind_rustls_0_22(&app_config.listen_addr, tls_rustls_config)
-            .map_err(|e| ShadowError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?
-    } else {
-        server_builder
-            .bind(&app_config.listen_addr)
-            .map_err(|e| ShadowError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?
+    // plain http is always bound
+    let http_listener = listener::Listener {
+        addr: app_config.listen_addr.clone(),
+        tls: None,
+        proxy_protocol: app_config.proxy_protocol,
+        h2c: app_config.h2c,
+        max_request_body,
     };
+    server = listener::bind(server, &http_listener, keep_alive, app.clone())?;
+    if app_config.proxy_protocol {
+        info!("expecting proxy protocol headers on {}", app_config.listen_addr);
+    }
+    if app_config.h2c {
+        info!("accepting h2c on {}", app_config.listen_addr);
+    }
 
-    // handle graceful shutdown with signals
-    let server = server.shutdown_timeout(30); // 30 second graceful shutdown period
+    // https is bound on its own address when tls is configured
+    if let Some(tls_rustls_config) = load_rustls_config(&app_config)? {
+        info!("tls is enabled on {}.", app_config.tls_listen_addr);
+        let tls_listener = listener::Listener {
+            addr: app_config.tls_listen_addr.clone(),
+            tls: Some(tls_rustls_config),
+            proxy_protocol: app_config.tls_proxy_protocol,
+            h2c: false,
+            max_request_body,
+        };
+        server = listener::bind(server, &tls_listener, keep_alive, app)?;
+        if app_config.tls_proxy_protocol {
+            info!("expecting proxy protocol headers on {}", app_config.tls_listen_addr);
+        }
+    } else {
+        info!("tls is disabled (http only).");
+    }
+
+    match admin_server {
+        Some(admin_server) => futures_util::future::try_join(server.run(), admin_server)
+            .await
+            .map(|_| ())
+            .map_err(ShadowError::Io),
+        None => server.run().await.map_err(ShadowError::Io),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
 
-    server.run().await.map_err(ShadowError::Io)
-} 
\ No newline at end of file
+    #[test]
+    fn forwarded_host_does_not_cross_cache_keys() {
+        let policy = CacheKeyPolicy::from_json(&serde_json::json!({ "default": { "include_host": true } })).unwrap();
+        let key = |req: &HttpRequest| request_cache_key(&policy, req, &request_header_map(req));
+        let routed_to_a = TestRequest::get()
+            .uri("/page")
+            .insert_header((header::HOST, "a.example.com"))
+            .peer_addr("10.1.2.3:4000".parse().unwrap())
+            .insert_header(("x-forwarded-host", "b.example.com"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .insert_header((header::FORWARDED, "host=b.example.com;proto=https"))
+            .to_http_request();
+        let plain_a = TestRequest::get()
+            .uri("/page")
+            .insert_header((header::HOST, "a.example.com"))
+            .to_http_request();
+        let plain_b = TestRequest::get()
+            .uri("/page")
+            .insert_header((header::HOST, "b.example.com"))
+            .to_http_request();
+        assert_eq!(request_host(&routed_to_a), "a.example.com");
+        assert_eq!(key(&routed_to_a), key(&plain_a));
+        assert_ne!(key(&routed_to_a), key(&plain_b));
+    }
+}
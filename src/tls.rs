@@ -4,7 +4,6 @@ use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use std::fs::File;
 use std::io::BufReader;
-use std::path::Path;
 
 /// loads rustls server configuration from the certificate and key files
 /// specified in the application config.
@@ -45,11 +44,14 @@ pub fn load_rustls_config(config: &Config) -> Result<Option<ServerConfig>> {
     let private_key = PrivateKey(keys[0].clone());
     
     // create server config with modern TLS settings
-    let server_config = ServerConfig::builder()
+    let mut server_config = ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
         .with_single_cert(cert_chain, private_key)
         .map_err(|e| ShadowError::TlsConfig(format!("tls config error: {}", e)))?;
+
+    // clients choose http/2 through alpn, anything else gets http/1.1
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
     
     Ok(Some(server_config))
 } 
\ No newline at end of file
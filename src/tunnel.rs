@@ -0,0 +1,393 @@
+//! websocket tunnels.
+//!
+//! a request asking to upgrade its connection to websocket bypasses the
+//! cache and goes to the origin with the `Connection: upgrade` and `Upgrade`
+//! headers that are otherwise dropped as hop-by-hop. when the origin switches
+//! protocols, bytes are copied between the two connections unchanged until
+//! either side closes or nothing has been sent either way for the idle
+//! timeout. each tunnel logs what it carried when it closes.
+//!
+//! actix only hands over the raw connection for websocket upgrades, so
+//! requests asking for any other protocol are refused with `501`. the one
+//! exception is `h2c`, which clients offer optimistically and fall back from:
+//! those requests are forwarded as plain http/1.1 without their `Upgrade`.
+
+use actix_web::HttpRequest;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{future, stream, Stream, StreamExt};
+use http::header::{self, HeaderValue};
+use http::Version;
+use log::{debug, info};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+/// how much is read from the origin at once
+const READ_BUFFER: usize = 16 * 1024;
+
+/// request extension telling the fetcher to ask the origin for an upgrade to
+/// this protocol
+#[derive(Clone, Debug)]
+pub struct UpgradeProtocol(pub HeaderValue);
+
+/// the protocols an http/1.1 request with `Connection: upgrade` asks for,
+/// lowercased, `None` for requests not asking for an upgrade
+fn upgrade_tokens(req: &HttpRequest) -> Option<Vec<String>> {
+    if req.version() != Version::HTTP_11 {
+        return None;
+    }
+    let tokens = |name: header::HeaderName| -> Vec<String> {
+        req.headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim().to_ascii_lowercase())
+            .filter(|token| !token.is_empty())
+            .collect()
+    };
+    let protocols = tokens(header::UPGRADE);
+    let connection_upgrade = tokens(header::CONNECTION).iter().any(|token| token == "upgrade");
+    (connection_upgrade && !protocols.is_empty()).then_some(protocols)
+}
+
+/// protocol names without their version, as in `websocket/13`
+fn protocol_name(protocol: &str) -> &str {
+    protocol.split('/').next().unwrap_or_default().trim()
+}
+
+/// the `Upgrade` header of a websocket handshake, `None` for other requests
+pub fn requested_upgrade(req: &HttpRequest) -> Option<HeaderValue> {
+    let websocket = upgrade_tokens(req)?
+        .iter()
+        .any(|protocol| protocol_name(protocol) == "websocket");
+    if websocket {
+        req.headers().get(header::UPGRADE).cloned()
+    } else {
+        None
+    }
+}
+
+/// the protocols of an upgrade that cannot be tunnelled, `None` for requests
+/// that are not asking for one or ask for websocket or `h2c`
+pub fn unsupported_upgrade(req: &HttpRequest) -> Option<String> {
+    let protocols = upgrade_tokens(req)?;
+    let supported = protocols
+        .iter()
+        .any(|protocol| matches!(protocol_name(protocol), "websocket" | "h2c"));
+    (!supported).then(|| protocols.join(", "))
+}
+
+/// totals over all tunnels, for the health endpoint
+#[derive(Debug, Default)]
+pub struct TunnelStats {
+    active: AtomicU64,
+    opened: AtomicU64,
+    bytes_to_origin: AtomicU64,
+    bytes_to_client: AtomicU64,
+}
+
+impl TunnelStats {
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_to_origin(&self) -> u64 {
+        self.bytes_to_origin.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_to_client(&self) -> u64 {
+        self.bytes_to_client.load(Ordering::Relaxed)
+    }
+}
+
+/// what went through a tunnel, logged when it is dropped
+struct Tunnel {
+    label: String,
+    started: Instant,
+    /// when bytes last went either way
+    last_activity: Instant,
+    bytes_to_origin: u64,
+    bytes_to_client: u64,
+    /// what ended the tunnel, the first reason given wins
+    closed_by: Option<&'static str>,
+    stats: Arc<TunnelStats>,
+}
+
+impl Tunnel {
+    fn sent_to_origin(&mut self, bytes: usize) {
+        self.bytes_to_origin += bytes as u64;
+        self.stats.bytes_to_origin.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity = Instant::now();
+    }
+
+    fn sent_to_client(&mut self, bytes: usize) {
+        self.bytes_to_client += bytes as u64;
+        self.stats.bytes_to_client.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity = Instant::now();
+    }
+
+    fn close(&mut self, reason: &'static str) {
+        self.closed_by.get_or_insert(reason);
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+        info!(
+            "tunnel {} closed ({}) after {:.1?}: {} bytes to the origin, {} bytes to the client",
+            self.label,
+            self.closed_by.unwrap_or("client gone"),
+            self.started.elapsed(),
+            self.bytes_to_origin,
+            self.bytes_to_client,
+        );
+    }
+}
+
+/// both directions of a tunnel, driven by the client's response body
+struct Splice<O, P> {
+    reader: ReadHalf<O>,
+    writer: WriteHalf<O>,
+    payload: P,
+    /// what the client sent that the origin has not taken yet
+    pending: Bytes,
+    client_closed: bool,
+    tunnel: Tunnel,
+    /// held so that the request counts against its server while the tunnel is open
+    _origin_body: hyper::Body,
+}
+
+/// splices the client connection, whose bytes arrive as `payload`, with the
+/// origin connection taken over after its `101`. the returned stream carries
+/// the origin's bytes and is the body of the client's `101` response.
+///
+/// the client's bytes are copied while that body is polled rather than on a
+/// task of their own, since actix does not wake another task reading the
+/// payload when the client closes its end.
+pub fn splice<O, P, E>(
+    upgraded: O,
+    payload: P,
+    idle_timeout: Option<Duration>,
+    stats: Arc<TunnelStats>,
+    label: String,
+    origin_body: hyper::Body,
+) -> impl Stream<Item = io::Result<Bytes>> + 'static
+where
+    O: AsyncRead + AsyncWrite + 'static,
+    P: Stream<Item = Result<Bytes, E>> + Unpin + 'static,
+    E: fmt::Display,
+{
+    stats.opened.fetch_add(1, Ordering::Relaxed);
+    stats.active.fetch_add(1, Ordering::Relaxed);
+    let (reader, writer) = tokio::io::split(upgraded);
+    let splice = Splice {
+        reader,
+        writer,
+        payload,
+        pending: Bytes::new(),
+        client_closed: false,
+        tunnel: Tunnel {
+            label,
+            started: Instant::now(),
+            last_activity: Instant::now(),
+            bytes_to_origin: 0,
+            bytes_to_client: 0,
+            closed_by: None,
+            stats,
+        },
+        _origin_body: origin_body,
+    };
+
+    stream::unfold(Some(splice), move |state| async move {
+        let mut state = state?;
+        let mut buf = BytesMut::with_capacity(READ_BUFFER);
+        loop {
+            let last_activity = state.tunnel.last_activity;
+            let idle = async move {
+                match idle_timeout {
+                    Some(idle_timeout) => tokio::time::sleep_until((last_activity + idle_timeout).into()).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                read = state.reader.read_buf(&mut buf) => {
+                    return match read {
+                        Ok(0) => {
+                            state.tunnel.close("origin closed");
+                            None
+                        }
+                        Ok(read) => {
+                            state.tunnel.sent_to_client(read);
+                            Some((Ok(buf.freeze()), Some(state)))
+                        }
+                        Err(e) => {
+                            debug!("tunnel {}: origin read failed: {}", state.tunnel.label, e);
+                            state.tunnel.close("origin error");
+                            Some((Err(e), None))
+                        }
+                    };
+                }
+                chunk = state.payload.next(), if state.pending.is_empty() && !state.client_closed => {
+                    match chunk {
+                        Some(Ok(chunk)) => state.pending = chunk,
+                        Some(Err(e)) => {
+                            debug!("tunnel {}: client read failed: {}", state.tunnel.label, e);
+                            state.tunnel.close("client error");
+                            return None;
+                        }
+                        None => {
+                            // the origin sees the client's end and closes its side when done
+                            state.client_closed = true;
+                            state.tunnel.close("client closed");
+                            let _ = state.writer.shutdown().await;
+                        }
+                    }
+                }
+                written = state.writer.write(&state.pending), if !state.pending.is_empty() => {
+                    match written {
+                        Ok(written) => {
+                            state.pending.advance(written);
+                            state.tunnel.sent_to_origin(written);
+                        }
+                        Err(e) => {
+                            debug!("tunnel {}: origin write failed: {}", state.tunnel.label, e);
+                            state.tunnel.close("origin error");
+                            return None;
+                        }
+                    }
+                }
+                _ = idle => {
+                    state.tunnel.close("idle timeout");
+                    return None;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::pin::Pin;
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+
+    fn upgrade(connection: &str, upgrade: &str) -> TestRequest {
+        TestRequest::default()
+            .insert_header((header::CONNECTION, connection))
+            .insert_header((header::UPGRADE, upgrade))
+    }
+
+    #[test]
+    fn websocket_handshakes_are_recognised() {
+        let requested = |req: TestRequest| requested_upgrade(&req.to_http_request());
+        assert_eq!(requested(upgrade("Upgrade", "websocket")).unwrap(), "websocket");
+        assert!(requested(upgrade("keep-alive, UPGRADE", "WebSocket")).is_some());
+        assert!(requested(upgrade("upgrade", "websocket/13")).is_some());
+        assert!(requested(upgrade("upgrade", "h2c, websocket")).is_some());
+        assert!(requested(upgrade("keep-alive", "websocket")).is_none());
+        assert!(requested(upgrade("upgrade", "websockets")).is_none());
+        assert!(requested(TestRequest::default().insert_header((header::CONNECTION, "upgrade"))).is_none());
+        // upgrades only exist in http/1.1
+        assert!(requested(upgrade("upgrade", "websocket").version(Version::HTTP_10)).is_none());
+        assert!(requested(upgrade("upgrade", "websocket").version(Version::HTTP_2)).is_none());
+    }
+
+    #[test]
+    fn other_upgrades_are_unsupported() {
+        let unsupported = |req: TestRequest| unsupported_upgrade(&req.to_http_request());
+        assert_eq!(unsupported(upgrade("upgrade", "TLS/1.0, IRC/6.9")).unwrap(), "tls/1.0, irc/6.9");
+        assert!(unsupported(upgrade("upgrade", "websocket")).is_none());
+        assert!(unsupported(upgrade("Upgrade, HTTP2-Settings", "h2c")).is_none());
+        assert!(unsupported(upgrade("keep-alive", "TLS/1.0")).is_none());
+        assert!(unsupported(upgrade("upgrade", "TLS/1.0").version(Version::HTTP_2)).is_none());
+    }
+
+    type Chunks = Pin<Box<dyn Stream<Item = io::Result<Bytes>>>>;
+    type Client = mpsc::UnboundedSender<io::Result<Bytes>>;
+
+    /// a client whose bytes are sent through the channel, closing when it is dropped
+    fn client() -> (Client, Chunks) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let payload = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        });
+        (sender, Box::pin(payload))
+    }
+
+    fn tunnel(idle_timeout: Option<Duration>, stats: &Arc<TunnelStats>) -> (Client, DuplexStream, Chunks) {
+        let (proxy, origin) = tokio::io::duplex(1024);
+        let (client, payload) = client();
+        let body = splice(proxy, payload, idle_timeout, stats.clone(), "test".into(), hyper::Body::empty());
+        (client, origin, Box::pin(body))
+    }
+
+    #[tokio::test]
+    async fn bytes_are_copied_and_counted_both_ways() {
+        let stats = Arc::new(TunnelStats::default());
+        let (client, mut origin, mut body) = tunnel(None, &stats);
+        assert_eq!((stats.opened(), stats.active()), (1, 1));
+
+        client.send(Ok(Bytes::from_static(b"hello"))).unwrap();
+        let origin_side = async {
+            let mut received = [0; 5];
+            origin.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"hello");
+            origin.write_all(b"world!").await.unwrap();
+        };
+        let (to_client, ()) = tokio::join!(body.next(), origin_side);
+        assert_eq!(to_client.unwrap().unwrap(), "world!");
+        assert_eq!((stats.bytes_to_origin(), stats.bytes_to_client()), (5, 6));
+
+        // the origin closing ends the tunnel
+        drop(origin);
+        assert!(body.next().await.is_none());
+        drop(body);
+        assert_eq!(stats.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_closing_client_is_passed_on_to_the_origin() {
+        let stats = Arc::new(TunnelStats::default());
+        let (client, mut origin, mut body) = tunnel(None, &stats);
+        client.send(Ok(Bytes::from_static(b"bye"))).unwrap();
+        drop(client);
+
+        // the origin reads what was sent and then the client's end, and may
+        // still answer before it closes its side
+        let origin_side = async {
+            let mut received = Vec::new();
+            origin.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"bye");
+            origin.write_all(b"ok").await.unwrap();
+            origin
+        };
+        let (to_client, origin) = tokio::join!(body.next(), origin_side);
+        assert_eq!(to_client.unwrap().unwrap(), "ok");
+        drop(origin);
+        assert!(body.next().await.is_none());
+        assert_eq!((stats.bytes_to_origin(), stats.bytes_to_client()), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn idle_tunnels_are_closed() {
+        let stats = Arc::new(TunnelStats::default());
+        let idle_timeout = Duration::from_millis(50);
+        let (_client, _origin, mut body) = tunnel(Some(idle_timeout), &stats);
+        let started = Instant::now();
+        let next = tokio::time::timeout(Duration::from_secs(5), body.next()).await;
+        assert!(next.unwrap().is_none());
+        assert!(started.elapsed() >= idle_timeout);
+        drop(body);
+        assert_eq!(stats.active(), 0);
+    }
+}
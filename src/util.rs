@@ -23,9 +23,21 @@ pub enum ShadowError {
     #[error("invalid header name: {0}")]
     InvalidHeaderName(#[from] http::header::InvalidHeaderName),
 
+    #[error("origin did not respond within {0:?}")]
+    OriginTimeout(std::time::Duration),
+
+    #[error("could not connect to the origin within {0:?}")]
+    ConnectTimeout(std::time::Duration),
+
+    #[error("circuit breaker of the origin is open, retrying in {0:?}")]
+    CircuitOpen(std::time::Duration),
+
     #[error("cache error: {0}")]
     Cache(String),
 
+    #[error("configuration error: {0}")]
+    Config(String),
+
     #[error("tls configuration error: {0}")]
     TlsConfig(String),
 